@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use chrono::Utc;
+use itertools::Itertools;
+use serde::Serialize;
+
+use crate::area::Area;
+use crate::config::{AlertMetric, AlertRuleConfig, AlertScope, AlertsConfigInner};
+use crate::database::{Totals, compute_ratios};
+use crate::locales::MinecraftLocale;
+use crate::params::{AreasIds, GroupBy, TimeBound, Uuids};
+use crate::players::PlayerExclusions;
+use crate::query::Filters;
+use crate::servers::{DEFAULT_SERVER, Server, Servers};
+use crate::values::ItemValues;
+use crate::webhook::post_message;
+
+
+/// An alert rule with its state, as listed by the `/alerts` endpoint. Webhook URLs are secret
+/// and not listed.
+#[derive(Serialize, Debug, Clone)]
+pub struct Alert {
+    pub id: String,
+    pub description: Option<String>,
+    pub server: String,
+    pub areas: Vec<String>,
+    pub material: Option<String>,
+    pub window: String,
+    pub per: AlertScope,
+    pub metric: AlertMetric,
+    pub below: Option<f64>,
+    pub above: Option<f64>,
+    pub state: AlertState
+}
+
+/// The state of an alert rule, as of its last evaluation.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct AlertState {
+    /// When the rule was last evaluated, and the error it failed with, if any.
+    pub last_run: Option<i64>,
+    pub error: Option<String>,
+
+    /// When the rule last fired, i.e. when it last found a new breach.
+    pub last_fired: Option<i64>,
+
+    /// The ongoing breaches of the rule, the most severe first.
+    pub breaches: Vec<Breach>
+}
+
+/// A player, area or total whose ratio crosses a threshold of an alert rule.
+#[derive(Serialize, Debug, Clone)]
+pub struct Breach {
+    /// The UUID of the player, the ID of the area, or `total`.
+    pub subject: String,
+    pub name: String,
+    pub value: f64,
+
+    /// When the breach was first found, and whether it was notified. A breach is notified only
+    /// once, until it ends; if the webhook fails, it is retried at the next evaluation.
+    pub since: i64,
+    pub notified: bool
+}
+
+/// A validated alert rule.
+struct Rule {
+    id: String,
+    server: String,
+    config: AlertRuleConfig
+}
+
+/// The alert rules, periodically evaluated against the ratios by a background task, and their
+/// states. States are kept in memory: ongoing breaches are notified again after a restart.
+pub(crate) struct Alerts {
+    rules: Vec<Rule>,
+    webhook: Option<String>,
+    states: Mutex<HashMap<String, AlertState>>
+}
+
+impl Alerts {
+    /// Validates the configured rules against the servers.
+    pub fn load(config: AlertsConfigInner, servers: &Servers) -> Result<Self, Vec<String>> {
+        let mut rules = vec![];
+        let mut errors = vec![];
+
+        for (id, rule) in config.rules.into_iter().sorted_by(|(a, _), (b, _)| a.cmp(b)) {
+            let server = rule.server.clone().unwrap_or_else(|| String::from(DEFAULT_SERVER));
+
+            if servers.get(&server).is_none() {
+                errors.push(format!("alert {}: the server {} is unknown", id, server));
+            }
+
+            if rule.below.is_none() && rule.above.is_none() {
+                errors.push(format!("alert {}: a `below` or `above` threshold is required", id));
+            }
+
+            if let Err(error) = TimeBound::parse(&rule.window) {
+                errors.push(format!("alert {}: invalid window: {}", id, error));
+            }
+
+            rules.push(Rule { id, server, config: rule });
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        Ok(Alerts {
+            rules,
+            webhook: config.webhook,
+            states: Mutex::new(HashMap::new())
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Lists the rules with their current state.
+    pub fn list(&self) -> Vec<Alert> {
+        let states = match self.states.lock() {
+            Ok(states) => states,
+            Err(poisoned) => poisoned.into_inner()
+        };
+
+        self.rules.iter()
+            .map(|rule| Alert {
+                id: rule.id.clone(),
+                description: rule.config.description.clone(),
+                server: rule.server.clone(),
+                areas: rule.config.areas.clone(),
+                material: rule.config.material.clone(),
+                window: rule.config.window.clone(),
+                per: rule.config.per,
+                metric: rule.config.metric,
+                below: rule.config.below,
+                above: rule.config.above,
+                state: states.get(&rule.id).cloned().unwrap_or_default()
+            })
+            .collect()
+    }
+
+    /// Starts the background task evaluating every rule at the given interval, in seconds.
+    pub fn start(self: Arc<Self>, servers: Servers, locale: Arc<MinecraftLocale>, values: ItemValues, exclusions: PlayerExclusions, interval: u64) {
+        thread::spawn(move || loop {
+            for rule in self.rules.iter() {
+                // Rules are validated against the servers when loaded.
+                if let Some(server) = servers.get(&rule.server) {
+                    let breaches = evaluate(rule, server, &locale, &values, &exclusions);
+                    self.update(rule, server, breaches);
+                }
+            }
+
+            thread::sleep(Duration::from_secs(interval));
+        });
+    }
+
+    /// Merges the results of an evaluation into the state of a rule, and notifies the new
+    /// breaches.
+    fn update(&self, rule: &Rule, server: &Server, breaches: Result<Vec<(String, String, f64)>, String>) {
+        let now = Utc::now().timestamp();
+        let mut state = self.state(&rule.id);
+        state.last_run = Some(now);
+
+        let breaches = match breaches {
+            Ok(breaches) => breaches,
+            Err(error) => {
+                eprintln!("Unable to evaluate alert {}: {}", rule.id, error);
+                state.error = Some(error);
+                self.set_state(&rule.id, state);
+                return;
+            }
+        };
+
+        state.error = None;
+
+        // Ongoing breaches keep their start time and notification status; ended ones are
+        // dropped, so that they are notified again if they happen again.
+        let previous: HashMap<String, Breach> = state.breaches.drain(..)
+            .map(|breach| (breach.subject.clone(), breach))
+            .collect();
+
+        state.breaches = breaches.into_iter()
+            .map(|(subject, name, value)| match previous.get(&subject) {
+                Some(breach) => Breach { name, value, ..breach.clone() },
+                None => Breach { subject, name, value, since: now, notified: false }
+            })
+            .collect();
+
+        if state.breaches.iter().any(|breach| breach.since == now) {
+            state.last_fired = Some(now);
+        }
+
+        let pending: Vec<&Breach> = state.breaches.iter().filter(|breach| !breach.notified).collect();
+
+        if !pending.is_empty() {
+            if let Some(webhook) = rule.config.webhook.as_ref().or(self.webhook.as_ref()) {
+                match post_message(webhook, &message(rule, server, &pending)) {
+                    Ok(()) => state.breaches.iter_mut().for_each(|breach| breach.notified = true),
+                    Err(error) => eprintln!("Unable to notify alert {}: {}", rule.id, error)
+                }
+            }
+        }
+
+        self.set_state(&rule.id, state);
+    }
+
+    fn state(&self, id: &str) -> AlertState {
+        match self.states.lock() {
+            Ok(states) => states.get(id).cloned().unwrap_or_default(),
+            Err(poisoned) => poisoned.into_inner().get(id).cloned().unwrap_or_default()
+        }
+    }
+
+    fn set_state(&self, id: &str, state: AlertState) {
+        let mut states = match self.states.lock() {
+            Ok(states) => states,
+            Err(poisoned) => poisoned.into_inner()
+        };
+
+        states.insert(id.to_string(), state);
+    }
+}
+
+/// Computes the ratios of a rule, and returns the subject, name and value of those crossing
+/// its thresholds, the most severe first. Ratios are not cached: the window is relative, so
+/// cached ratios would lag behind by up to the cache lifetime.
+fn evaluate(rule: &Rule, server: &Server, locale: &Arc<MinecraftLocale>, values: &ItemValues, exclusions: &PlayerExclusions) -> Result<Vec<(String, String, f64)>, String> {
+    let config = &rule.config;
+
+    let areas: Vec<Area> = server.areas.get().filter(AreasIds::list(config.areas.clone())).areas.iter().map(|(_, a)| a.clone()).collect();
+    if areas.is_empty() {
+        return Err(String::from("There are no areas matching this rule."));
+    }
+
+    let mut filters = Filters::new(areas, Uuids::any(), TimeBound::parse(&config.window)?, TimeBound::unbounded());
+    filters.materials = config.material.iter().map(|material| material.trim().to_lowercase()).collect();
+
+    let group_by = match config.per {
+        AlertScope::Player => GroupBy::Player,
+        AlertScope::Area => GroupBy::Area,
+        AlertScope::Total => GroupBy::None
+    };
+
+    // Like for `/ratios`, non-player entries (e.g. hoppers) are ignored in players breakdowns.
+    if group_by == GroupBy::Player {
+        filters.exclusions = Some(exclusions.clone());
+    }
+
+    let ratios = compute_ratios(server.source.blocking(), filters, group_by, locale, values)
+        .map_err(|error| error.to_string())?;
+
+    let metric = |totals: &Totals| match config.metric {
+        AlertMetric::Ratio => totals.global as f64,
+        AlertMetric::Value => totals.value
+    };
+
+    let subjects: Vec<(String, String, f64)> = match config.per {
+        AlertScope::Player => ratios.players.unwrap_or_default().into_iter()
+            .map(|(uuid, breakdown)| (uuid, breakdown.name, metric(&breakdown.totals)))
+            .collect(),
+        AlertScope::Area => ratios.areas.unwrap_or_default().into_iter()
+            .map(|(id, breakdown)| (id, breakdown.name, metric(&breakdown.totals)))
+            .collect(),
+        AlertScope::Total => vec![(String::from("total"), String::from("Total"), metric(&ratios.totals))]
+    };
+
+    let below = |value: f64| config.below.map(|below| value < below).unwrap_or(false);
+    let above = |value: f64| config.above.map(|above| value > above).unwrap_or(false);
+
+    Ok(subjects.into_iter()
+        .filter(|(_, _, value)| below(*value) || above(*value))
+        .sorted_by(|(_, _, a), (_, _, b)| match config.below.is_some() {
+            true => a.partial_cmp(b),
+            false => b.partial_cmp(a)
+        }.unwrap_or(std::cmp::Ordering::Equal))
+        .collect())
+}
+
+/// Formats the notification of new breaches of a rule.
+fn message(rule: &Rule, server: &Server, breaches: &[&Breach]) -> String {
+    let config = &rule.config;
+
+    let metric = match config.metric {
+        AlertMetric::Ratio => "net ratio",
+        AlertMetric::Value => "net value"
+    };
+
+    let thresholds = vec![
+        config.below.map(|below| format!("below {}", below)),
+        config.above.map(|above| format!("above {}", above))
+    ].into_iter().flatten().join(" or ");
+
+    let areas = match config.areas.is_empty() {
+        true => String::from("every area"),
+        false => config.areas.join(", ")
+    };
+
+    let mut message = format!(
+        "🚨 **{}** on {}: {} of {} in {} since {} {}",
+        rule.id,
+        server.name,
+        metric,
+        config.material.as_deref().unwrap_or("all items"),
+        areas,
+        config.window,
+        thresholds
+    );
+
+    if let Some(description) = &config.description {
+        message.push_str(&format!("\n{}", description));
+    }
+
+    for breach in breaches {
+        message.push_str(&format!("\n- {}: {}", breach.name, breach.value));
+    }
+
+    message
+}
@@ -1,21 +1,182 @@
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use itertools::Itertools;
 use serde::Serialize;
 
-use crate::config::{AreasConfig, ConfigArea};
+use crate::config::{ConfigArea, ConfigAreaEntry, ConfigShape};
+use crate::query::Sql;
 
 
 /// All areas declared into the configuration file are stored in this structure, made available
 /// through a state.
+///
+/// Areas can be gathered into groups, either explicitly (a list of areas or groups IDs) or by
+/// nesting sub-areas into an area. A nested area without shapes of its own is only a group.
+/// An ID can therefore be an area, a group, or both (an area with sub-areas).
 #[derive(Serialize, Debug, Clone)]
 pub(crate) struct Areas {
-    pub areas: HashMap<String, Area>
+    pub areas: HashMap<String, Area>,
+    pub groups: HashMap<String, AreaGroup>,
+
+    /// The IDs of the top-level entries of the configuration, sorted.
+    pub roots: Vec<String>
+}
+
+/// A group of areas. Its members are areas or groups IDs.
+#[derive(Serialize, Debug, Clone)]
+pub struct AreaGroup {
+    pub id: String,
+    pub name: String,
+    pub members: Vec<String>
+}
+
+/// A node of the areas tree returned by the `/areas` endpoint. `area` is set if the node is an
+/// area (and not only a group); `children` lists its sub-areas or the members of the group.
+#[derive(Serialize, Debug, Clone)]
+pub struct AreaNode {
+    pub id: String,
+    pub name: String,
+    pub area: Option<Area>,
+    pub children: Vec<AreaNode>
 }
 
-impl From<AreasConfig> for Areas {
-    fn from(config: AreasConfig) -> Self {
-        Self {
-            areas: config.areas.into_iter().map(|(id, area)| (id.clone(), Area::from(id.clone(), area))).collect()
+impl Areas {
+    /// Returns the IDs of the areas designated by the given ID: the area itself, and all areas of
+    /// the group, recursively. Unknown IDs designate no area.
+    pub fn expand(&self, id: &str) -> Vec<String> {
+        let mut ids = vec![];
+
+        if self.areas.contains_key(id) {
+            ids.push(id.to_string());
+        }
+
+        if let Some(group) = self.groups.get(id) {
+            for member in group.members.iter() {
+                ids.extend(self.expand(member));
+            }
+        }
+
+        ids.into_iter().unique().collect()
+    }
+
+    /// Builds the areas tree, from the top-level entries of the configuration.
+    pub fn tree(&self) -> Vec<AreaNode> {
+        self.roots.iter().map(|id| self.node(id)).collect()
+    }
+
+    pub fn node(&self, id: &str) -> AreaNode {
+        let area = self.areas.get(id).cloned();
+        let group = self.groups.get(id);
+
+        AreaNode {
+            id: id.to_string(),
+            name: area.as_ref().map(|area| area.name.clone())
+                .or(group.map(|group| group.name.clone()))
+                .unwrap_or_default(),
+            children: group
+                .map(|group| group.members.iter().map(|member| self.node(member)).collect())
+                .unwrap_or_default(),
+            area
+        }
+    }
+
+    /// Loads an entry of the configuration (and its sub-areas, recursively).
+    fn load(&mut self, id: String, entry: ConfigAreaEntry, parent_world: Option<&str>, errors: &mut Vec<String>) {
+        match entry {
+            ConfigAreaEntry::Group(members) => {
+                self.groups.insert(id.clone(), AreaGroup { name: id.clone(), id, members });
+            },
+            ConfigAreaEntry::NamedGroup { name, group } => {
+                self.groups.insert(id.clone(), AreaGroup { id, name, members: group });
+            },
+            ConfigAreaEntry::Area(mut config) => {
+                let world = match config.world.clone().or(parent_world.map(String::from)) {
+                    Some(world) => world,
+                    None => return errors.push(format!("area {}: a world is required", id))
+                };
+
+                let sub_areas: Vec<(String, ConfigAreaEntry)> = config.areas.drain()
+                    .map(|(sub_id, entry)| (format!("{}.{}", id, sub_id), entry))
+                    .sorted_by(|(a, _), (b, _)| a.cmp(b))
+                    .collect();
+
+                if !sub_areas.is_empty() {
+                    self.groups.insert(id.clone(), AreaGroup {
+                        id: id.clone(),
+                        name: config.name.clone(),
+                        members: sub_areas.iter().map(|(sub_id, _)| sub_id.clone()).collect()
+                    });
+                }
+
+                // An area with sub-areas but no shape of its own is only a group.
+                let has_shapes = config.pos1.is_some() || config.pos2.is_some() || !config.shapes.is_empty();
+                if has_shapes || sub_areas.is_empty() {
+                    match Area::from(id.clone(), world.clone(), config) {
+                        Ok(area) => { self.areas.insert(id, area); },
+                        Err(error) => errors.push(error)
+                    }
+                }
+
+                for (sub_id, entry) in sub_areas {
+                    self.load(sub_id, entry, Some(&world), errors);
+                }
+            }
+        }
+    }
+
+    /// Checks that groups members exist, and that groups do not contain themselves.
+    fn validate_groups(&self, errors: &mut Vec<String>) {
+        for group in self.groups.values().sorted_by(|a, b| a.id.cmp(&b.id)) {
+            for member in group.members.iter() {
+                if !self.areas.contains_key(member) && !self.groups.contains_key(member) {
+                    errors.push(format!("group {}: unknown area or group {}", group.id, member));
+                }
+            }
+
+            if self.contains_cycle(&group.id, &mut vec![]) {
+                errors.push(format!("group {}: a group cannot contain itself", group.id));
+            }
+        }
+    }
+
+    fn contains_cycle(&self, id: &str, path: &mut Vec<String>) -> bool {
+        if path.iter().any(|visited| visited == id) {
+            return true;
+        }
+
+        path.push(id.to_string());
+
+        let cycle = self.groups.get(id)
+            .map(|group| group.members.iter().any(|member| self.contains_cycle(member, path)))
+            .unwrap_or(false);
+
+        path.pop();
+        cycle
+    }
+}
+
+impl TryFrom<HashMap<String, ConfigAreaEntry>> for Areas {
+    type Error = Vec<String>;
+
+    /// Builds the areas from their configuration entries, returning all invalid areas errors, if
+    /// any.
+    fn try_from(entries: HashMap<String, ConfigAreaEntry>) -> Result<Self, Self::Error> {
+        let mut areas = Areas {
+            areas: HashMap::new(),
+            groups: HashMap::new(),
+            roots: entries.keys().cloned().sorted().collect()
+        };
+        let mut errors = vec![];
+
+        for (id, entry) in entries {
+            areas.load(id, entry, None, &mut errors);
+        }
+
+        areas.validate_groups(&mut errors);
+
+        match errors.len() {
+            0 => Ok(areas),
+            _ => Err(errors)
         }
     }
 }
@@ -38,45 +199,528 @@ pub fn cache_key_for_vec_areas(areas: &Vec<Area>) -> String {
         .collect()
 }
 
-/// Represents an area where players can access chests or other containers. Prism results
+/// Sorts areas in the order transactions are attributed to them when they overlap: the most
+/// specific first (sub-areas before their parent), then by ID, so that the attribution is the
+/// same whatever the order areas were loaded or requested in.
+pub fn sort_by_specificity(areas: &mut Vec<Area>) {
+    areas.sort_by(|a, b| {
+        b.id.matches('.').count().cmp(&a.id.matches('.').count())
+            .then_with(|| a.id.cmp(&b.id))
+    });
+}
+
+/// Represents an area where players can access chests or other containers. Transactions
 /// will be filtered in these areas only, because we don't want to get the ratio of players
 /// from the whole maps.
+///
+/// An area is the union of its `shapes`, minus its `exclude` shapes. `low_corner` and
+/// `high_corner` are the corners of its bounding box.
 #[derive(Serialize, Debug, Clone)]
 pub struct Area {
     pub id: String,
     pub name: String,
     pub world: String,
     pub low_corner: Vec<i64>,
-    pub high_corner: Vec<i64>
+    pub high_corner: Vec<i64>,
+    pub shapes: Vec<Shape>,
+    pub exclude: Vec<Shape>
 }
 
 impl Area {
-    pub fn from(id: String, config: ConfigArea) -> Self {
-        Area {
+    /// Builds an area from its configuration, checking that it is valid.
+    pub fn from(id: String, world: String, config: ConfigArea) -> Result<Self, String> {
+        let mut shapes = vec![];
+
+        match (config.pos1, config.pos2) {
+            (Some(pos1), Some(pos2)) => shapes.push(Shape::cuboid(&pos1, &pos2)?),
+            (None, None) => (),
+            _ => return Err(format!("area {}: pos1 and pos2 must be set together", id))
+        }
+
+        for shape in config.shapes {
+            shapes.push(Shape::from(shape).map_err(|e| format!("area {}: {}", id, e))?);
+        }
+
+        if shapes.is_empty() {
+            return Err(format!("area {}: at least one shape is required (pos1/pos2 or shapes)", id));
+        }
+
+        let exclude = config.exclude.into_iter()
+            .map(|shape| Shape::from(shape).map_err(|e| format!("area {}: excluded zone: {}", id, e)))
+            .collect::<Result<Vec<Shape>, String>>()?;
+
+        let (low_corner, high_corner) = shapes.iter()
+            .map(|shape| shape.bounding_box())
+            .fold1(|(low_a, high_a), (low_b, high_b)| (
+                (0..3).map(|i| low_a[i].min(low_b[i])).collect(),
+                (0..3).map(|i| high_a[i].max(high_b[i])).collect()
+            ))
+            .unwrap_or_default();
+
+        Ok(Area {
             id,
             name: config.name,
-            world: config.world.replace("'", "\\'"),
-            low_corner: vec![
-                config.pos1[0].min(config.pos2[0]),
-                config.pos1[1].min(config.pos2[1]),
-                config.pos1[2].min(config.pos2[2]),
-            ],
-            high_corner: vec![
-                config.pos1[0].max(config.pos2[0]),
-                config.pos1[1].max(config.pos2[1]),
-                config.pos1[2].max(config.pos2[2]),
-            ]
-        }
-    }
-
-    /// Generates an SQL WHERE clause to filter for this area, assuming that the `prism_worlds`
-    /// table is aliased as `w`, and the `prism_data` one, as `d`.
-    pub fn as_sql(&self) -> String {
-        format!(
-            "w.world = '{}' AND d.x > {} AND d.y > {} AND d.z > {} AND d.x < {} AND d.y < {} AND d.z < {}",
-            self.world,
-            self.low_corner[0], self.low_corner[1], self.low_corner[2],
-            self.high_corner[0], self.high_corner[1], self.high_corner[2]
-        )
+            world,
+            low_corner,
+            high_corner,
+            shapes,
+            exclude
+        })
+    }
+
+    /// Generates an SQL WHERE clause to filter for this area, assuming that the container
+    /// history rows (see `crate::source`) are aliased as `h`.
+    ///
+    /// The bounding box is checked first so the database can discard most rows cheaply.
+    pub fn as_sql(&self) -> Sql {
+        let bounding_box = Sql::new("h.x >= ? AND h.y >= ? AND h.z >= ? AND h.x <= ? AND h.y <= ? AND h.z <= ?")
+            .bind(self.low_corner[0]).bind(self.low_corner[1]).bind(self.low_corner[2])
+            .bind(self.high_corner[0]).bind(self.high_corner[1]).bind(self.high_corner[2]);
+
+        let mut clauses = vec![
+            Sql::new("h.world = ?").bind(self.world.as_str()),
+            bounding_box,
+            Sql::or(self.shapes.iter().map(|shape| shape.as_sql()).collect())
+        ];
+
+        if !self.exclude.is_empty() {
+            clauses.push(Sql::compose("NOT ({})", vec![
+                Sql::or(self.exclude.iter().map(|shape| shape.as_sql()).collect())
+            ]));
+        }
+
+        Sql::and(clauses)
+    }
+
+    /// Checks if a location is in this area, with the same rules as [`Area::as_sql`].
+    pub fn contains(&self, world: &str, x: i64, y: i64, z: i64) -> bool {
+        let position = [x, y, z];
+
+        world == self.world
+            && (0..3).all(|i| position[i] >= self.low_corner[i] && position[i] <= self.high_corner[i])
+            && self.shapes.iter().any(|shape| shape.contains(x, y, z))
+            && !self.exclude.iter().any(|shape| shape.contains(x, y, z))
+    }
+}
+
+
+/// A shape composing an area (or excluded from it).
+///
+/// - A `cuboid` is an axis-aligned box between two corners, bounds excluded.
+/// - A `polygon` is a 2D polygon on the X/Z plane, bounds included, extruded between `y_min`
+///   and `y_max` (both included).
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Shape {
+    Cuboid {
+        low_corner: Vec<i64>,
+        high_corner: Vec<i64>
+    },
+    Polygon {
+        points: Vec<(i64, i64)>,
+        y_min: i64,
+        y_max: i64
+    }
+}
+
+impl Shape {
+    fn from(config: ConfigShape) -> Result<Self, String> {
+        match config {
+            ConfigShape::Cuboid { pos1, pos2 } => Shape::cuboid(&pos1, &pos2),
+            ConfigShape::Polygon { polygon, y } => {
+                if polygon.len() < 3 {
+                    return Err(String::from("a polygon needs at least three points"));
+                }
+
+                if polygon.iter().any(|point| point.len() != 2) {
+                    return Err(String::from("polygon points must be [x, z] pairs"));
+                }
+
+                if polygon.iter().map(|point| point[1]).all_equal() || polygon.iter().map(|point| point[0]).all_equal() {
+                    return Err(String::from("a polygon cannot be flat"));
+                }
+
+                if y.len() != 2 {
+                    return Err(String::from("the Y range of a polygon must be a [min, max] pair"));
+                }
+
+                Ok(Shape::Polygon {
+                    points: polygon.iter().map(|point| (point[0], point[1])).collect(),
+                    y_min: y[0].min(y[1]),
+                    y_max: y[0].max(y[1])
+                })
+            }
+        }
+    }
+
+    fn cuboid(pos1: &Vec<i64>, pos2: &Vec<i64>) -> Result<Self, String> {
+        if pos1.len() != 3 || pos2.len() != 3 {
+            return Err(String::from("cuboid corners must be [x, y, z] triplets"));
+        }
+
+        Ok(Shape::Cuboid {
+            low_corner: (0..3).map(|i| pos1[i].min(pos2[i])).collect(),
+            high_corner: (0..3).map(|i| pos1[i].max(pos2[i])).collect()
+        })
+    }
+
+    /// Returns the low and high corners of the bounding box of this shape.
+    fn bounding_box(&self) -> (Vec<i64>, Vec<i64>) {
+        match self {
+            Shape::Cuboid { low_corner, high_corner } => (low_corner.clone(), high_corner.clone()),
+            Shape::Polygon { points, y_min, y_max } => (
+                vec![
+                    points.iter().map(|(x, _)| *x).min().unwrap_or_default(),
+                    *y_min,
+                    points.iter().map(|(_, z)| *z).min().unwrap_or_default()
+                ],
+                vec![
+                    points.iter().map(|(x, _)| *x).max().unwrap_or_default(),
+                    *y_max,
+                    points.iter().map(|(_, z)| *z).max().unwrap_or_default()
+                ]
+            )
+        }
+    }
+
+    /// Checks if a location is in this shape, with the same rules as [`Shape::as_sql`].
+    fn contains(&self, x: i64, y: i64, z: i64) -> bool {
+        match self {
+            Shape::Cuboid { low_corner, high_corner } =>
+                x > low_corner[0] && y > low_corner[1] && z > low_corner[2]
+                    && x < high_corner[0] && y < high_corner[1] && z < high_corner[2],
+
+            Shape::Polygon { points, y_min, y_max } => {
+                let edges = || points.iter().zip(points.iter().cycle().skip(1));
+
+                let crossings = edges()
+                    .filter(|((_, z1), (_, z2))| z1 != z2)
+                    .map(|(a, b)| if a.1 < b.1 { (a, b) } else { (b, a) })
+                    .filter(|((x1, z1), (x2, z2))| z >= *z1 && z < *z2 && (x - x1) * (z2 - z1) < (x2 - x1) * (z - z1))
+                    .count();
+
+                let on_edge = edges().any(|((x1, z1), (x2, z2))|
+                    (x - x1) * (z2 - z1) == (z - z1) * (x2 - x1)
+                        && x >= *x1.min(x2) && x <= *x1.max(x2)
+                        && z >= *z1.min(z2) && z <= *z1.max(z2)
+                );
+
+                y >= *y_min && y <= *y_max && (crossings % 2 == 1 || on_edge)
+            }
+        }
+    }
+
+    /// Generates an SQL WHERE clause to filter for this shape, assuming that the container
+    /// history rows are aliased as `h`. Only plain arithmetic is used, so that it works with any database.
+    fn as_sql(&self) -> Sql {
+        match self {
+            Shape::Cuboid { low_corner, high_corner } =>
+                Sql::new("h.x > ? AND h.y > ? AND h.z > ? AND h.x < ? AND h.y < ? AND h.z < ?")
+                    .bind(low_corner[0]).bind(low_corner[1]).bind(low_corner[2])
+                    .bind(high_corner[0]).bind(high_corner[1]).bind(high_corner[2]),
+
+            Shape::Polygon { points, y_min, y_max } => {
+                // Ray casting: a point is inside if a ray starting from it crosses an odd number
+                // of edges. For each edge (ordered so that z1 < z2; horizontal edges never cross),
+                // the ray crosses it if z1 <= z < z2 and if the point is on the left of the edge,
+                // i.e. (x - x1) * (z2 - z1) < (x2 - x1) * (z - z1), which avoids any division.
+                let crossings: Vec<Sql> = points.iter()
+                    .zip(points.iter().cycle().skip(1))
+                    .filter(|((_, z1), (_, z2))| z1 != z2)
+                    .map(|(a, b)| if a.1 < b.1 { (a, b) } else { (b, a) })
+                    .map(|((x1, z1), (x2, z2))|
+                        Sql::new("(h.z >= ? AND h.z < ? AND (h.x - ?) * ? < ? * (h.z - ?))")
+                            .bind(*z1).bind(*z2)
+                            .bind(*x1).bind(z2 - z1)
+                            .bind(x2 - x1).bind(*z1)
+                    )
+                    .collect();
+
+                // On-edge points are not reliably caught by ray casting; as bounds are included,
+                // the edges themselves are checked too.
+                let on_edges: Vec<Sql> = points.iter()
+                    .zip(points.iter().cycle().skip(1))
+                    .map(|((x1, z1), (x2, z2))|
+                        Sql::new("((h.x - ?) * ? = (h.z - ?) * ? AND h.x >= ? AND h.x <= ? AND h.z >= ? AND h.z <= ?)")
+                            .bind(*x1).bind(z2 - z1)
+                            .bind(*z1).bind(x2 - x1)
+                            .bind(*x1.min(x2)).bind(*x1.max(x2))
+                            .bind(*z1.min(z2)).bind(*z1.max(z2))
+                    )
+                    .collect();
+
+                let inside = Sql::compose(
+                    &format!("({}) % 2 = 1", crossings.iter().map(|_| "{}").join(" + ")),
+                    crossings
+                );
+
+                Sql::and(vec![
+                    Sql::new("h.y >= ? AND h.y <= ?").bind(*y_min).bind(*y_max),
+                    Sql::or(vec![inside, Sql::or(on_edges)])
+                ])
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use mysql::Value;
+    use rusqlite::Connection;
+    use rusqlite::types::Value as SqliteValue;
+
+    use super::*;
+
+    /// Builds areas from the TOML content of an `areas` configuration section.
+    fn areas(config: &str) -> Result<Areas, Vec<String>> {
+        let entries: HashMap<String, ConfigAreaEntry> = toml::from_str(config).expect("invalid TOML");
+        Areas::try_from(entries)
+    }
+
+    /// Builds a single area, declared as `test`.
+    fn area(config: &str) -> Area {
+        let mut areas = areas(&format!("test = {}", config)).expect("invalid area");
+        areas.areas.remove("test").unwrap()
+    }
+
+    /// Checks if a location is in an area according to its SQL clause, run by SQLite.
+    fn sql_contains(area: &Area, world: &str, x: i64, y: i64, z: i64) -> bool {
+        let sql = Sql::compose("SELECT COUNT(*) FROM (SELECT ? AS world, ? AS x, ? AS y, ? AS z) h WHERE {}", vec![
+            area.as_sql()
+        ]);
+
+        let values: Vec<SqliteValue> = vec![
+            SqliteValue::Text(world.to_string()),
+            SqliteValue::Integer(x),
+            SqliteValue::Integer(y),
+            SqliteValue::Integer(z)
+        ].into_iter().chain(sql.values().iter().map(|value| match value {
+            Value::Int(int) => SqliteValue::Integer(*int),
+            Value::Bytes(bytes) => SqliteValue::Text(String::from_utf8(bytes.clone()).unwrap()),
+            value => panic!("unexpected value {:?}", value)
+        })).collect();
+
+        let connection = Connection::open_in_memory().unwrap();
+        let count: i64 = connection.query_row(sql.text(), values, |row| row.get(0)).unwrap();
+
+        count > 0
+    }
+
+    /// Checks that both [`Area::contains`] and [`Area::as_sql`] give the expected result for
+    /// each location.
+    fn assert_contains(area: &Area, locations: &[((i64, i64, i64), bool)]) {
+        for ((x, y, z), expected) in locations {
+            assert_eq!(area.contains("world", *x, *y, *z), *expected, "contains {:?}", (x, y, z));
+            assert_eq!(sql_contains(area, "world", *x, *y, *z), *expected, "as_sql {:?}", (x, y, z));
+        }
+    }
+
+    #[test]
+    fn area_sql_values() {
+        let area = area(r#"{ name = "Test", world = "world", pos1 = [10, 64, 10], pos2 = [0, 0, 0], exclude = [
+            { pos1 = [4, 0, 4], pos2 = [6, 64, 6] }
+        ] }"#);
+        let sql = area.as_sql();
+
+        assert_eq!(
+            sql.text(),
+            "(h.world = ?) \
+            AND (h.x >= ? AND h.y >= ? AND h.z >= ? AND h.x <= ? AND h.y <= ? AND h.z <= ?) \
+            AND ((h.x > ? AND h.y > ? AND h.z > ? AND h.x < ? AND h.y < ? AND h.z < ?)) \
+            AND (NOT ((h.x > ? AND h.y > ? AND h.z > ? AND h.x < ? AND h.y < ? AND h.z < ?)))"
+        );
+
+        let expected: Vec<Value> = vec![Value::Bytes(b"world".to_vec())].into_iter()
+            .chain(vec![0i64, 0, 0, 10, 64, 10, 0, 0, 0, 10, 64, 10, 4, 0, 4, 6, 64, 6].into_iter().map(Value::Int))
+            .collect();
+        assert_eq!(sql.values(), &expected[..]);
+    }
+
+    #[test]
+    fn cuboid_bounds_are_excluded() {
+        let cuboid = area(r#"{ name = "Test", world = "world", pos1 = [10, 10, 10], pos2 = [0, 0, 0] }"#);
+
+        assert_contains(&cuboid, &[
+            ((5, 5, 5), true),
+            ((1, 1, 1), true),
+            ((9, 9, 9), true),
+            ((0, 5, 5), false),
+            ((10, 5, 5), false),
+            ((5, 0, 5), false),
+            ((5, 10, 5), false),
+            ((5, 5, 0), false),
+            ((5, 5, 10), false),
+            ((0, 0, 0), false),
+            ((10, 10, 10), false),
+            ((-1, 5, 5), false),
+            ((5, 5, 11), false)
+        ]);
+    }
+
+    #[test]
+    fn polygon_bounds_are_included() {
+        let triangle = area(r#"{ name = "Test", world = "world", shapes = [{ polygon = [[0, 0], [10, 0], [0, 10]], y = [5, 0] }] }"#);
+
+        assert_contains(&triangle, &[
+            // Inside
+            ((2, 3, 2), true),
+            ((1, 3, 8), true),
+            // Vertices
+            ((0, 3, 0), true),
+            ((10, 3, 0), true),
+            ((0, 3, 10), true),
+            // Edges
+            ((5, 3, 0), true),
+            ((0, 3, 5), true),
+            ((5, 3, 5), true),
+            ((3, 3, 7), true),
+            // Outside
+            ((6, 3, 6), false),
+            ((4, 3, 7), false),
+            ((11, 3, 0), false),
+            ((-1, 3, 0), false),
+            ((0, 3, -1), false),
+            // Y range
+            ((2, 0, 2), true),
+            ((2, 5, 2), true),
+            ((2, -1, 2), false),
+            ((2, 6, 2), false)
+        ]);
+    }
+
+    #[test]
+    fn concave_polygon() {
+        let l_shape = area(r#"{ name = "Test", world = "world", shapes = [
+            { polygon = [[0, 0], [10, 0], [10, 5], [5, 5], [5, 10], [0, 10]], y = [0, 10] }
+        ] }"#);
+
+        assert_contains(&l_shape, &[
+            ((2, 5, 5), true),
+            ((2, 5, 2), true),
+            ((7, 5, 3), true),
+            ((2, 5, 8), true),
+            ((7, 5, 5), true),
+            ((5, 5, 7), true),
+            ((5, 5, 5), true),
+            ((7, 5, 7), false),
+            ((9, 5, 9), false),
+            ((6, 5, 6), false)
+        ]);
+    }
+
+    #[test]
+    fn shapes_are_merged() {
+        let area = area(r#"{ name = "Test", world = "world", shapes = [
+            { pos1 = [0, 0, 0], pos2 = [10, 10, 10] },
+            { pos1 = [20, 0, 0], pos2 = [30, 10, 10] }
+        ] }"#);
+
+        assert_eq!(area.low_corner, vec![0, 0, 0]);
+        assert_eq!(area.high_corner, vec![30, 10, 10]);
+
+        assert_contains(&area, &[
+            ((5, 5, 5), true),
+            ((25, 5, 5), true),
+            ((15, 5, 5), false),
+            ((20, 5, 5), false)
+        ]);
+    }
+
+    #[test]
+    fn excluded_shapes() {
+        let area = area(r#"{ name = "Test", world = "world", pos1 = [0, 0, 0], pos2 = [20, 20, 20], exclude = [
+            { pos1 = [2, 2, 2], pos2 = [6, 6, 6] },
+            { polygon = [[10, 10], [15, 10], [15, 15], [10, 15]], y = [0, 20] }
+        ] }"#);
+
+        assert_contains(&area, &[
+            ((18, 18, 18), true),
+            // Cuboid exclusion, bounds excluded (so they are in the area)
+            ((4, 4, 4), false),
+            ((2, 4, 4), true),
+            ((6, 4, 4), true),
+            // Polygon exclusion, bounds included (so they are not in the area)
+            ((12, 5, 12), false),
+            ((10, 5, 12), false),
+            ((15, 5, 15), false),
+            ((16, 5, 12), true),
+            ((9, 5, 9), true)
+        ]);
+    }
+
+    #[test]
+    fn world_must_match() {
+        let cuboid = area(r#"{ name = "Test", world = "world", pos1 = [0, 0, 0], pos2 = [10, 10, 10] }"#);
+
+        assert!(!cuboid.contains("world_nether", 5, 5, 5));
+        assert!(!sql_contains(&cuboid, "world_nether", 5, 5, 5));
+    }
+
+    #[test]
+    fn invalid_shapes() {
+        assert!(areas(r#"test = { name = "Test", world = "world", pos1 = [0, 0, 0] }"#).is_err());
+        assert!(areas(r#"test = { name = "Test", world = "world", pos1 = [0, 0], pos2 = [1, 1] }"#).is_err());
+        assert!(areas(r#"test = { name = "Test", pos1 = [0, 0, 0], pos2 = [1, 1, 1] }"#).is_err());
+        assert!(areas(r#"test = { name = "Test", world = "world", shapes = [{ polygon = [[0, 0], [1, 1]], y = [0, 1] }] }"#).is_err());
+        assert!(areas(r#"test = { name = "Test", world = "world", shapes = [{ polygon = [[0, 0], [1, 0], [2, 0]], y = [0, 1] }] }"#).is_err());
+    }
+
+    #[test]
+    fn groups_are_expanded() {
+        let areas = areas(r#"
+            bank = { name = "Bank", world = "world", pos1 = [0, 0, 0], pos2 = [10, 10, 10] }
+            town = { name = "Town", world = "world", pos1 = [0, 0, 0], pos2 = [100, 100, 100], areas = {
+                market = { name = "Market", pos1 = [20, 0, 20], pos2 = [30, 10, 30] },
+                docks = { name = "Docks", areas = {
+                    north = { name = "North docks", pos1 = [40, 0, 40], pos2 = [50, 10, 50] }
+                } }
+            } }
+            all = ["bank", "town", "bank"]
+        "#).unwrap();
+
+        assert_eq!(areas.areas["town.market"].world, "world");
+        assert!(!areas.areas.contains_key("town.docks"));
+
+        assert_eq!(areas.expand("bank"), vec!["bank"]);
+        assert_eq!(areas.expand("town.docks"), vec!["town.docks.north"]);
+        assert_eq!(areas.expand("town"), vec!["town", "town.docks.north", "town.market"]);
+        assert_eq!(areas.expand("all"), vec!["bank", "town", "town.docks.north", "town.market"]);
+        assert!(areas.expand("unknown").is_empty());
+    }
+
+    #[test]
+    fn groups_cannot_contain_themselves() {
+        let errors = areas(r#"
+            a = ["b"]
+            b = ["c"]
+            c = ["a"]
+            d = ["d"]
+            e = ["a"]
+        "#).unwrap_err();
+
+        assert_eq!(errors, vec![
+            "group a: a group cannot contain itself",
+            "group b: a group cannot contain itself",
+            "group c: a group cannot contain itself",
+            "group d: a group cannot contain itself",
+            "group e: a group cannot contain itself"
+        ]);
+
+        assert_eq!(areas(r#"a = ["unknown"]"#).unwrap_err(), vec!["group a: unknown area or group unknown"]);
+    }
+
+    #[test]
+    fn specific_areas_come_first() {
+        let mut areas: Vec<Area> = areas(r#"
+            bank = { name = "Bank", world = "world", pos1 = [0, 0, 0], pos2 = [10, 10, 10] }
+            town = { name = "Town", world = "world", pos1 = [0, 0, 0], pos2 = [100, 100, 100], areas = {
+                market = { name = "Market", pos1 = [20, 0, 20], pos2 = [30, 10, 30] }
+            } }
+        "#).unwrap().into();
+
+        sort_by_specificity(&mut areas);
+
+        assert_eq!(areas.iter().map(|area| area.id.as_str()).collect::<Vec<_>>(), vec!["town.market", "bank", "town"]);
     }
 }
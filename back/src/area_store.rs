@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+
+use serde::{Serialize, Deserialize};
+
+use crate::area::Areas;
+use crate::config::ConfigAreaEntry;
+use crate::database::clear_areas_caches;
+
+
+/// Areas created, updated or deleted through the API, persisted in a TOML file next to the
+/// configuration. Its `areas` have the same format as the `areas` configuration section and
+/// override it; `deleted` lists the configuration areas deleted through the API.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AreasOverlay {
+    #[serde(default)]
+    pub areas: HashMap<String, ConfigAreaEntry>,
+    #[serde(default)]
+    pub deleted: Vec<String>
+}
+
+/// An area or group to create through the API, with the same format as an entry of the `areas`
+/// configuration section.
+#[derive(Deserialize, Debug)]
+pub struct AreaCreation {
+    pub id: String,
+    pub area: ConfigAreaEntry
+}
+
+/// Stores the live areas of a server. Areas are built by merging the configuration and the
+/// overlay; any change is validated, persisted to the overlay file, then applied, and the caches
+/// depending on areas are cleared.
+pub(crate) struct AreaStore {
+    base: HashMap<String, ConfigAreaEntry>,
+    overlay_path: PathBuf,
+    overlay: Mutex<AreasOverlay>,
+    areas: RwLock<Arc<Areas>>
+}
+
+impl AreaStore {
+    /// Loads the areas from the configuration and the overlay file, if it exists. An overlay
+    /// file which can't be read fails, as the next change would overwrite it.
+    pub fn load(base: HashMap<String, ConfigAreaEntry>, overlay_path: PathBuf) -> Result<Self, Vec<String>> {
+        let overlay: AreasOverlay = match fs::read_to_string(&overlay_path) {
+            Ok(content) => toml::from_str(&content)
+                .map_err(|e| vec![format!("Unable to parse areas overlay file {:?}: {}", overlay_path, e)])?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => AreasOverlay::default(),
+            Err(e) => return Err(vec![format!("Unable to read areas overlay file {:?}: {}", overlay_path, e)])
+        };
+
+        let areas = Self::build(&base, &overlay)?;
+
+        Ok(AreaStore {
+            base,
+            overlay_path,
+            overlay: Mutex::new(overlay),
+            areas: RwLock::new(Arc::new(areas))
+        })
+    }
+
+    /// Returns the current areas. The returned snapshot is not affected by later changes.
+    pub fn get(&self) -> Arc<Areas> {
+        match self.areas.read() {
+            Ok(areas) => Arc::clone(&areas),
+            Err(poisoned) => Arc::clone(&poisoned.into_inner())
+        }
+    }
+
+    /// Creates a top-level area or group, failing if one already exists with this ID.
+    pub fn create(&self, id: String, entry: ConfigAreaEntry) -> Result<Arc<Areas>, Vec<String>> {
+        Self::check_id(&id)?;
+
+        self.update(|base, overlay| {
+            if Self::exists(base, overlay, &id) {
+                return Err(vec![format!("area {}: an area or group already exists with this ID", id)]);
+            }
+
+            overlay.deleted.retain(|deleted| deleted != &id);
+            overlay.areas.insert(id, entry);
+            Ok(())
+        })
+    }
+
+    /// Creates or replaces a top-level area or group.
+    pub fn put(&self, id: String, entry: ConfigAreaEntry) -> Result<Arc<Areas>, Vec<String>> {
+        Self::check_id(&id)?;
+
+        self.update(|_, overlay| {
+            overlay.deleted.retain(|deleted| deleted != &id);
+            overlay.areas.insert(id, entry);
+            Ok(())
+        })
+    }
+
+    /// Deletes a top-level area or group.
+    pub fn delete(&self, id: String) -> Result<Arc<Areas>, Vec<String>> {
+        self.update(|base, overlay| {
+            if !Self::exists(base, overlay, &id) {
+                return Err(vec![format!("area {}: unknown area or group", id)]);
+            }
+
+            overlay.areas.remove(&id);
+            if base.contains_key(&id) && !overlay.deleted.contains(&id) {
+                overlay.deleted.push(id);
+            }
+            Ok(())
+        })
+    }
+
+    fn check_id(id: &str) -> Result<(), Vec<String>> {
+        // Other characters either have a meaning in IDs (dots separate sub-areas IDs, commas
+        // areas IDs in query parameters) or in URLs, where IDs are used as path segments.
+        match !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+            true => Ok(()),
+            false => Err(vec![format!("area {}: IDs must only contain letters, digits, _ and -", id)])
+        }
+    }
+
+    /// Checks if a top-level area or group exists with this ID.
+    fn exists(base: &HashMap<String, ConfigAreaEntry>, overlay: &AreasOverlay, id: &str) -> bool {
+        overlay.areas.contains_key(id) || (base.contains_key(id) && !overlay.deleted.iter().any(|d| d == id))
+    }
+
+    /// Applies a change to the overlay. The overlay stays locked from the moment the change
+    /// sees it until it's saved, so checks done by the change still hold when it's applied.
+    fn update<F>(&self, change: F) -> Result<Arc<Areas>, Vec<String>>
+        where F: FnOnce(&HashMap<String, ConfigAreaEntry>, &mut AreasOverlay) -> Result<(), Vec<String>>
+    {
+        let mut overlay = self.lock_overlay();
+
+        let mut updated = overlay.clone();
+        change(&self.base, &mut updated)?;
+
+        let areas = Arc::new(Self::build(&self.base, &updated)?);
+        self.save(&updated)?;
+
+        *overlay = updated;
+        match self.areas.write() {
+            Ok(mut current) => *current = Arc::clone(&areas),
+            Err(poisoned) => *poisoned.into_inner() = Arc::clone(&areas)
+        }
+
+        clear_areas_caches();
+
+        Ok(areas)
+    }
+
+    fn build(base: &HashMap<String, ConfigAreaEntry>, overlay: &AreasOverlay) -> Result<Areas, Vec<String>> {
+        let mut entries = base.clone();
+
+        for deleted in overlay.deleted.iter() {
+            entries.remove(deleted);
+        }
+
+        entries.extend(overlay.areas.clone());
+
+        Areas::try_from(entries)
+    }
+
+    fn save(&self, overlay: &AreasOverlay) -> Result<(), Vec<String>> {
+        // Converting to a TOML value first ensures plain values are written before tables.
+        toml::Value::try_from(overlay)
+            .and_then(|value| toml::to_string(&value))
+            .map_err(|e| e.to_string())
+            .and_then(|content| fs::write(&self.overlay_path, content).map_err(|e| e.to_string()))
+            .map_err(|e| vec![format!("Unable to save areas overlay file {:?}: {}", self.overlay_path, e)])
+    }
+
+    // We only replace the overlay as a whole, after it was validated and saved, so it's safe to
+    // accept poisoned mutexes.
+    fn lock_overlay(&self) -> std::sync::MutexGuard<'_, AreasOverlay> {
+        match self.overlay.lock() {
+            Ok(overlay) => overlay,
+            Err(poisoned) => poisoned.into_inner()
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn area_ids() {
+        for id in &["bank", "old_bank", "spawn-2", "V5"] {
+            assert!(AreaStore::check_id(id).is_ok(), "{}", id);
+        }
+
+        for id in &["", "town.bank", "a,b", "a/b", "a?b", "a#b", "a b", "a%20b", "é"] {
+            assert!(AreaStore::check_id(id).is_err(), "{}", id);
+        }
+    }
+
+    #[test]
+    fn missing_overlay_is_empty() {
+        let path = std::env::temp_dir().join(format!("panoptes-missing-overlay-{}.toml", std::process::id()));
+        let store = AreaStore::load(HashMap::new(), path).unwrap();
+
+        assert!(store.get().areas.is_empty());
+    }
+
+    #[test]
+    fn unreadable_overlay_fails() {
+        // Reading a directory fails with another error than `NotFound`.
+        let errors = AreaStore::load(HashMap::new(), std::env::temp_dir()).err().unwrap();
+
+        assert!(errors[0].starts_with("Unable to read areas overlay file"), "{:?}", errors);
+    }
+}
@@ -0,0 +1,173 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+use chrono::Utc;
+use rocket::http::uri::Origin;
+use serde::{Serialize, Deserialize};
+
+use crate::auth::User;
+use crate::config::{AuditConfig, AuditConfigInner};
+use crate::export::csv_field;
+use crate::params::TimeBound;
+use crate::users::Role;
+
+
+/// A call to an endpoint exposing players data, recorded into the audit log.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEntry {
+    pub timestamp: i64,
+    pub user: String,
+    pub role: Role,
+    pub endpoint: String,
+    pub parameters: String,
+
+    /// The number of results returned (players, items, buckets, transactions…), or `None` if
+    /// the call failed or for streams, as it is unknown when they are opened.
+    pub results: Option<usize>,
+
+    /// The error returned if the call failed, be it rejected (e.g. an unknown area) or because
+    /// the query failed.
+    pub error: Option<String>
+}
+
+impl AuditEntry {
+    /// Formats this entry as a CSV line, without the line break.
+    pub fn to_csv(&self) -> String {
+        vec![
+            self.timestamp.to_string(),
+            csv_field(&self.user),
+            self.role.to_string(),
+            csv_field(&self.endpoint),
+            csv_field(&self.parameters),
+            self.results.map(|results| results.to_string()).unwrap_or_default(),
+            self.error.as_ref().map(|error| csv_field(error)).unwrap_or_default()
+        ].join(",")
+    }
+}
+
+pub const AUDIT_CSV_HEADER: &str = "timestamp,user,role,endpoint,parameters,results,error";
+
+
+/// The filters of the `/audit` endpoint. `endpoint` matches the start of the endpoint, and
+/// `search` any part of the parameters (e.g. a player UUID).
+#[derive(Debug, Clone)]
+pub struct AuditFilters {
+    pub user: Option<String>,
+    pub endpoint: Option<String>,
+    pub search: Option<String>,
+    pub since: TimeBound,
+    pub until: TimeBound
+}
+
+impl AuditFilters {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.user.as_ref().map(|user| &entry.user == user).unwrap_or(true)
+            && self.endpoint.as_ref().map(|endpoint| entry.endpoint.starts_with(endpoint.as_str())).unwrap_or(true)
+            && self.search.as_ref().map(|search| entry.parameters.contains(search.as_str())).unwrap_or(true)
+            && self.since.epoch.map(|since| entry.timestamp >= since).unwrap_or(true)
+            && self.until.epoch.map(|until| entry.timestamp < until).unwrap_or(true)
+    }
+}
+
+
+/// An append-only audit log recording who looked up which players data, stored as JSON lines
+/// in a local file. Made available through a state.
+///
+/// Entries are written by a dedicated thread, so that recording a call never blocks a request
+/// on the file I/O; this also serializes writes, so that lines are never interleaved.
+pub struct AuditLog {
+    path: PathBuf,
+    sender: Mutex<Sender<AuditEntry>>
+}
+
+impl AuditLog {
+    /// Records a call to the current endpoint by the given user, with its outcome: the number
+    /// of results (see [`AuditEntry::results`]) or the error returned.
+    ///
+    /// The log being a safeguard and not a feature of the endpoint, a failure to write it is
+    /// reported but doesn't fail the request.
+    pub fn record(&self, user: &User, uri: &Origin<'_>, outcome: Result<Option<usize>, String>) {
+        let (results, error) = match outcome {
+            Ok(results) => (results, None),
+            Err(error) => (None, Some(error))
+        };
+
+        let entry = AuditEntry {
+            timestamp: Utc::now().timestamp(),
+            user: user.name.clone(),
+            role: user.role,
+            endpoint: uri.path().to_string(),
+            parameters: uri.query().map(|query| query.to_string()).unwrap_or_default(),
+            results,
+            error
+        };
+
+        let sent = match self.sender.lock() {
+            Ok(sender) => sender.send(entry).is_ok(),
+            Err(poisoned) => poisoned.into_inner().send(entry).is_ok()
+        };
+
+        if !sent {
+            eprintln!("Unable to write audit log {:?}: the writer stopped", self.path);
+        }
+    }
+
+    /// Returns the entries matching the filters, most recent first, up to `limit` entries if
+    /// set. Unreadable lines are skipped.
+    pub fn entries(&self, filters: &AuditFilters, limit: Option<usize>) -> Result<Vec<AuditEntry>, String> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.to_string())
+        };
+
+        Ok(content.lines()
+            .rev()
+            .filter_map(|line| serde_json::from_str::<AuditEntry>(line).ok())
+            .filter(|entry| filters.matches(entry))
+            .take(limit.unwrap_or(usize::MAX))
+            .collect())
+    }
+}
+
+impl From<AuditConfig> for AuditLog {
+    fn from(config: AuditConfig) -> Self {
+        config.audit.into()
+    }
+}
+
+impl From<AuditConfigInner> for AuditLog {
+    fn from(config: AuditConfigInner) -> Self {
+        let (sender, receiver) = mpsc::channel::<AuditEntry>();
+        let path = config.file.clone();
+
+        // The writer stops once the log is dropped, and with it the sender.
+        thread::spawn(move || {
+            for entry in receiver {
+                if let Err(e) = append(&path, &entry) {
+                    eprintln!("Unable to write audit log {:?}: {}", path, e);
+                }
+            }
+        });
+
+        AuditLog {
+            path: config.file,
+            sender: Mutex::new(sender)
+        }
+    }
+}
+
+fn append(path: &Path, entry: &AuditEntry) -> Result<(), String> {
+    let line = serde_json::to_string(entry).map_err(|e| e.to_string())?;
+
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", line))
+        .map_err(|e| e.to_string())
+}
@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use chrono::Utc;
+use rocket::http::Status;
+use rocket::request::{self, Request, FromRequest, Outcome};
+use rocket::State;
+use serde::{Serialize, Deserialize};
+
+use crate::config::{AuthConfig, AuthConfigInner};
+use crate::users::{Role, UserStore, generate_token, hash_token};
+
+
+/// The name of the cookie storing the session token, for browsers.
+pub const SESSION_COOKIE: &str = "panoptes_session";
+
+/// The number of failed logins allowed for a user or a client before they have to wait.
+const FREE_LOGIN_FAILURES: u32 = 3;
+
+/// The maximal delay before a new login attempt, in seconds.
+const MAX_LOGIN_BACKOFF: i64 = 300;
+
+/// The time after which failed logins are forgotten, in seconds.
+const LOGIN_FAILURES_LIFETIME: i64 = 3600;
+
+/// Authenticates requests, either with an API token (created with the `users` command line) or
+/// with a session token (obtained through `/login`), sent as an `Authorization: Bearer <token>`
+/// header or, for sessions, as a cookie. Made available through a state.
+///
+/// Sessions are kept in memory and don't survive restarts. They only store the user name, so
+/// role changes and user removals apply immediately.
+///
+/// To slow down password guessing, failed logins are counted per user name and per client IP:
+/// past a few failures, each new attempt has to wait for an exponentially growing delay.
+pub struct Authenticator {
+    pub users: UserStore,
+    pub secure_cookie: bool,
+    sessions: Mutex<HashMap<String, Session>>,
+    login_failures: Mutex<HashMap<String, LoginFailures>>,
+    session_lifetime: i64,
+    anonymous_role: Option<Role>
+}
+
+struct Session {
+    name: String,
+    expires: i64
+}
+
+struct LoginFailures {
+    count: u32,
+    last: i64
+}
+
+impl LoginFailures {
+    /// The timestamp before which no new attempt is allowed.
+    fn retry_after(&self) -> i64 {
+        match self.count.checked_sub(FREE_LOGIN_FAILURES) {
+            Some(exponent) => self.last + 2i64.saturating_pow(exponent).min(MAX_LOGIN_BACKOFF),
+            None => self.last
+        }
+    }
+}
+
+/// The reason a login failed.
+#[derive(Debug, PartialEq)]
+pub enum LoginError {
+    InvalidCredentials,
+
+    /// Too many logins failed recently for this user or client, which must wait for the given
+    /// number of seconds.
+    Throttled(i64)
+}
+
+impl Authenticator {
+    /// Checks the credentials of a user logging in from the given client, returning their name
+    /// and role. Fails without checking them if too many logins failed recently.
+    pub fn login(&self, credentials: &Credentials, client: Option<IpAddr>) -> Result<(String, Role), LoginError> {
+        let now = Utc::now().timestamp();
+        let keys: Vec<String> = std::iter::once(format!("user:{}", credentials.name.to_lowercase()))
+            .chain(client.map(|ip| format!("ip:{}", ip)))
+            .collect();
+
+        let mut failures = match self.login_failures.lock() {
+            Ok(failures) => failures,
+            Err(poisoned) => poisoned.into_inner()
+        };
+
+        failures.retain(|_, failure| failure.last + LOGIN_FAILURES_LIFETIME > now);
+
+        let retry_after = keys.iter()
+            .filter_map(|key| failures.get(key))
+            .map(|failure| failure.retry_after())
+            .max()
+            .unwrap_or(now);
+
+        if retry_after > now {
+            return Err(LoginError::Throttled(retry_after - now));
+        }
+
+        match self.users.authenticate_password(&credentials.name, &credentials.password) {
+            Some(user) => {
+                for key in keys.iter() {
+                    failures.remove(key);
+                }
+
+                Ok(user)
+            },
+            None => {
+                for key in keys {
+                    let failure = failures.entry(key).or_insert(LoginFailures { count: 0, last: now });
+                    failure.count += 1;
+                    failure.last = now;
+                }
+
+                Err(LoginError::InvalidCredentials)
+            }
+        }
+    }
+
+    /// Opens a session for the given user, returning its token and expiration timestamp.
+    pub fn open_session(&self, name: String) -> (String, i64) {
+        let token = generate_token();
+        let expires = Utc::now().timestamp() + self.session_lifetime;
+
+        if let Ok(mut sessions) = self.sessions.lock() {
+            let now = Utc::now().timestamp();
+            sessions.retain(|_, session| session.expires > now);
+            sessions.insert(hash_token(&token), Session { name, expires });
+        }
+
+        (token, expires)
+    }
+
+    /// Closes the session with the given token, if any.
+    pub fn close_session(&self, token: &str) {
+        if let Ok(mut sessions) = self.sessions.lock() {
+            sessions.remove(&hash_token(token));
+        }
+    }
+
+    /// Returns the user authenticated by this token, be it an API token or a session one.
+    pub fn authenticate(&self, token: &str) -> Option<User> {
+        if let Some((name, role)) = self.users.authenticate_token(token) {
+            return Some(User { name, role, session: None });
+        }
+
+        let name = {
+            let sessions = self.sessions.lock().ok()?;
+            let session = sessions.get(&hash_token(token))?;
+
+            if session.expires <= Utc::now().timestamp() {
+                return None;
+            }
+
+            session.name.clone()
+        };
+
+        let users = self.users.users().ok()?;
+        let user = users.users.get(&name)?;
+
+        Some(User { name, role: user.role, session: Some(token.to_string()) })
+    }
+}
+
+impl From<AuthConfig> for Authenticator {
+    fn from(config: AuthConfig) -> Self {
+        config.auth.into()
+    }
+}
+
+impl From<AuthConfigInner> for Authenticator {
+    fn from(config: AuthConfigInner) -> Self {
+        Authenticator {
+            users: UserStore::new(config.users_file),
+            secure_cookie: config.secure_cookie,
+            sessions: Mutex::new(HashMap::new()),
+            login_failures: Mutex::new(HashMap::new()),
+            session_lifetime: config.session_lifetime as i64,
+            anonymous_role: config.anonymous_role
+        }
+    }
+}
+
+
+/// The credentials sent to `/login`.
+#[derive(Deserialize, Debug)]
+pub struct Credentials {
+    pub name: String,
+    pub password: String
+}
+
+/// A session opened by `/login`.
+#[derive(Serialize, Debug)]
+pub struct SessionInfo {
+    pub token: String,
+    pub name: String,
+    pub role: Role,
+    pub expires: i64
+}
+
+
+/// A request guard returning the authenticated user.
+///
+/// If the request has no credentials and an `anonymous_role` is configured, an `anonymous` user
+/// with this role is returned. Invalid credentials are always rejected.
+#[derive(Serialize, Debug, Clone)]
+pub struct User {
+    pub name: String,
+    pub role: Role,
+
+    /// The session token, if authenticated with a session.
+    #[serde(skip)]
+    pub session: Option<String>
+}
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for User {
+    type Error = ();
+
+    async fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let authenticator = try_outcome!(request.guard::<State<'r, Authenticator>>().await);
+
+        let token = request.headers().get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_string())
+            .or_else(|| request.cookies().get(SESSION_COOKIE).map(|cookie| cookie.value().to_string()));
+
+        match (token, authenticator.anonymous_role) {
+            (Some(token), _) => match authenticator.authenticate(&token) {
+                Some(user) => Outcome::Success(user),
+                None => Outcome::Failure((Status::Unauthorized, ()))
+            },
+            (None, Some(role)) => Outcome::Success(User { name: String::from("anonymous"), role, session: None }),
+            (None, None) => Outcome::Failure((Status::Unauthorized, ()))
+        }
+    }
+}
+
+/// A request guard returning the IP address of the client, if known (see
+/// [`Request::client_ip`]).
+pub struct ClientIp(pub Option<IpAddr>);
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for ClientIp {
+    type Error = ();
+
+    async fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(ClientIp(request.client_ip()))
+    }
+}
+
+/// Declares a request guard ensuring the authenticated user has at least the given role.
+macro_rules! role_guard {
+    ($(#[$doc:meta])* $guard:ident, $role:expr) => {
+        $(#[$doc])*
+        pub struct $guard(pub User);
+
+        #[rocket::async_trait]
+        impl<'a, 'r> FromRequest<'a, 'r> for $guard {
+            type Error = ();
+
+            async fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+                let user = try_outcome!(request.guard::<User>().await);
+
+                match user.role >= $role {
+                    true => Outcome::Success($guard(user)),
+                    false => Outcome::Failure((Status::Forbidden, ()))
+                }
+            }
+        }
+    };
+}
+
+role_guard!(
+    /// A request guard ensuring the user is at least a viewer.
+    ///
+    /// ```rust
+    /// #[get("/aggregated")]
+    /// fn aggregated(_viewer: Viewer) { }
+    /// ```
+    Viewer, Role::Viewer
+);
+
+role_guard!(
+    /// A request guard ensuring the user is at least a moderator.
+    Moderator, Role::Moderator
+);
+
+role_guard!(
+    /// A request guard ensuring the user is an administrator.
+    Admin, Role::Admin
+);
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn login_backoff() {
+        let failures = |count| LoginFailures { count, last: 1000 }.retry_after();
+
+        assert_eq!(failures(1), 1000);
+        assert_eq!(failures(FREE_LOGIN_FAILURES - 1), 1000);
+        assert_eq!(failures(FREE_LOGIN_FAILURES), 1001);
+        assert_eq!(failures(FREE_LOGIN_FAILURES + 1), 1002);
+        assert_eq!(failures(FREE_LOGIN_FAILURES + 4), 1016);
+        assert_eq!(failures(FREE_LOGIN_FAILURES + 60), 1000 + MAX_LOGIN_BACKOFF);
+    }
+}
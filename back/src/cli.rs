@@ -0,0 +1,130 @@
+use std::io::{self, BufRead};
+
+use figment::Figment;
+
+use crate::config::AuthConfig;
+use crate::users::{Role, StoredUser, UserStore, generate_token, hash_password, hash_token};
+
+
+const USAGE: &str = "
+Usage: panoptes-back users <command>
+
+    list                            Lists users, their role and their tokens labels.
+    add <name> <role>               Adds a user. The password is read from the standard input;
+                                    leave it empty for a user only using API tokens.
+    set-role <name> <role>          Changes the role of a user.
+    set-password <name>             Changes the password of a user, read from the standard input.
+    remove <name>                   Removes a user.
+    add-token <name> <label>        Creates an API token for a user, and prints it.
+    revoke-token <name> <label>     Revokes an API token of a user.
+
+Roles are viewer, moderator and admin.";
+
+
+/// Runs the command line, if the program was called with a command. Returns the exit code in
+/// that case, or `None` to launch the server.
+pub fn run(figment: &Figment) -> Option<i32> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match args.first().map(|arg| arg.as_str()) {
+        Some("users") => {},
+        Some(_) => {
+            eprintln!("{}", USAGE);
+            return Some(2);
+        },
+        None => return None
+    }
+
+    let config: AuthConfig = match figment.extract() {
+        Ok(config) => config,
+        Err(e) => {
+            rocket::config::pretty_print_error(e);
+            return Some(1);
+        }
+    };
+
+    match users(UserStore::new(config.auth.users_file), &args[1..]) {
+        Ok(()) => Some(0),
+        Err(error) => {
+            eprintln!("{}", error);
+            Some(1)
+        }
+    }
+}
+
+fn users(store: UserStore, args: &[String]) -> Result<(), String> {
+    let mut users = store.users()?;
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+
+    match args.as_slice() {
+        ["list"] => {
+            for (name, user) in users.users.iter() {
+                let tokens: Vec<&str> = user.tokens.keys().map(|label| label.as_str()).collect();
+                println!("{}\t{}\t{}", name, user.role, tokens.join(", "));
+            }
+            return Ok(());
+        },
+        ["add", name, role] => {
+            if users.users.contains_key(*name) {
+                return Err(format!("User {} already exists.", name));
+            }
+
+            let role: Role = role.parse()?;
+            let password = match read_password()? {
+                Some(password) => Some(hash_password(&password)?),
+                None => None
+            };
+
+            users.users.insert(name.to_string(), StoredUser { role, password, tokens: Default::default() });
+        },
+        ["set-role", name, role] => {
+            user(&mut users.users, name)?.role = role.parse()?;
+        },
+        ["set-password", name] => {
+            let password = read_password()?.ok_or_else(|| String::from("The password cannot be empty."))?;
+            user(&mut users.users, name)?.password = Some(hash_password(&password)?);
+        },
+        ["remove", name] => {
+            users.users.remove(*name).ok_or_else(|| format!("Unknown user {}.", name))?;
+        },
+        ["add-token", name, label] => {
+            let user = user(&mut users.users, name)?;
+            if user.tokens.contains_key(*label) {
+                return Err(format!("User {} already has a token labelled {}.", name, label));
+            }
+
+            let token = generate_token();
+            user.tokens.insert(label.to_string(), hash_token(&token));
+            store.save(&users)?;
+
+            println!("{}", token);
+            eprintln!("This token will not be displayed again.");
+            return Ok(());
+        },
+        ["revoke-token", name, label] => {
+            user(&mut users.users, name)?.tokens.remove(*label)
+                .ok_or_else(|| format!("User {} has no token labelled {}.", name, label))?;
+        },
+        _ => return Err(String::from(USAGE))
+    }
+
+    store.save(&users)
+}
+
+fn user<'a>(users: &'a mut std::collections::BTreeMap<String, StoredUser>, name: &str) -> Result<&'a mut StoredUser, String> {
+    users.get_mut(name).ok_or_else(|| format!("Unknown user {}.", name))
+}
+
+/// Reads a password from the first line of the standard input. Returns `None` if it's empty.
+fn read_password() -> Result<Option<String>, String> {
+    eprintln!("Password:");
+
+    let mut password = String::new();
+    io::stdin().lock().read_line(&mut password).map_err(|e| e.to_string())?;
+
+    let password = password.trim_end_matches(|c| c == '\n' || c == '\r');
+    match password.is_empty() {
+        true => Ok(None),
+        false => Ok(Some(password.to_string()))
+    }
+}
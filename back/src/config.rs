@@ -2,17 +2,47 @@ use std::collections::HashMap;
 use serde::{Serialize, Deserialize};
 use std::path::PathBuf;
 
+use crate::users::Role;
+
 #[derive(Serialize, Deserialize)]
 pub struct AreasConfig {
-    pub areas: HashMap<String, ConfigArea>,
+    #[serde(default)]
+    pub areas: HashMap<String, ConfigAreaEntry>,
+    #[serde(default = "default_areas_overlay")]
+    pub areas_overlay: PathBuf,
 }
 
-#[derive(Serialize, Deserialize)]
+fn default_areas_overlay() -> PathBuf {
+    PathBuf::from("../areas.toml")
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ConfigAreaEntry {
+    Group(Vec<String>),
+    NamedGroup { name: String, group: Vec<String> },
+    Area(ConfigArea),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ConfigArea {
     pub name: String,
-    pub world: String,
-    pub pos1: Vec<i64>,
-    pub pos2: Vec<i64>,
+    pub world: Option<String>,
+    pub pos1: Option<Vec<i64>>,
+    pub pos2: Option<Vec<i64>>,
+    #[serde(default)]
+    pub shapes: Vec<ConfigShape>,
+    #[serde(default)]
+    pub exclude: Vec<ConfigShape>,
+    #[serde(default)]
+    pub areas: HashMap<String, ConfigAreaEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ConfigShape {
+    Cuboid { pos1: Vec<i64>, pos2: Vec<i64> },
+    Polygon { polygon: Vec<Vec<i64>>, y: Vec<i64> },
 }
 
 
@@ -30,6 +60,387 @@ impl Default for CorsConfig {
 }
 
 
+#[derive(Serialize, Deserialize)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub auth: AuthConfigInner
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AuthConfigInner {
+    #[serde(default = "default_users_file")]
+    pub users_file: PathBuf,
+    #[serde(default = "default_session_lifetime")]
+    pub session_lifetime: u64,
+    #[serde(default = "default_secure_cookie")]
+    pub secure_cookie: bool,
+    pub anonymous_role: Option<Role>
+}
+
+impl Default for AuthConfigInner {
+    fn default() -> AuthConfigInner {
+        AuthConfigInner {
+            users_file: default_users_file(),
+            session_lifetime: default_session_lifetime(),
+            secure_cookie: default_secure_cookie(),
+            anonymous_role: None
+        }
+    }
+}
+
+fn default_users_file() -> PathBuf {
+    PathBuf::from("../users.toml")
+}
+
+fn default_session_lifetime() -> u64 {
+    12 * 3600
+}
+
+fn default_secure_cookie() -> bool {
+    true
+}
+
+
+#[derive(Serialize, Deserialize)]
+pub struct AuditConfig {
+    #[serde(default)]
+    pub audit: AuditConfigInner
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AuditConfigInner {
+    #[serde(default = "default_audit_file")]
+    pub file: PathBuf
+}
+
+impl Default for AuditConfigInner {
+    fn default() -> AuditConfigInner {
+        AuditConfigInner {
+            file: default_audit_file()
+        }
+    }
+}
+
+fn default_audit_file() -> PathBuf {
+    PathBuf::from("../audit.log")
+}
+
+
+#[derive(Serialize, Deserialize)]
+pub struct SourceConfig {
+    #[serde(default)]
+    pub source: SourceConfigInner,
+
+    /// The `databases.prism` section of previous versions, used if no source URL is set.
+    pub databases: Option<LegacyDatabasesConfig>
+}
+
+impl SourceConfig {
+    /// The source configuration, its URL and pool size falling back to the legacy
+    /// `databases.prism` ones.
+    pub fn resolve(self) -> SourceConfigInner {
+        let legacy = self.databases.and_then(|databases| databases.prism);
+        let (legacy_url, legacy_pool_size) = match legacy {
+            Some(prism) => (Some(prism.url), prism.pool_size),
+            None => (None, None)
+        };
+
+        SourceConfigInner {
+            url: self.source.url.or(legacy_url),
+            pool_size: self.source.pool_size.or(legacy_pool_size),
+            ..self.source
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SourceConfigInner {
+    #[serde(default)]
+    pub kind: SourceKind,
+    pub url: Option<String>,
+    #[serde(default = "default_table_prefix")]
+    pub table_prefix: String,
+
+    /// The maximal number of MySQL connections (see `DEFAULT_POOL_SIZE`).
+    pub pool_size: Option<usize>
+}
+
+impl Default for SourceConfigInner {
+    fn default() -> SourceConfigInner {
+        SourceConfigInner {
+            kind: SourceKind::default(),
+            url: None,
+            table_prefix: default_table_prefix(),
+            pool_size: None
+        }
+    }
+}
+
+fn default_table_prefix() -> String {
+    String::from("co_")
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceKind {
+    Prism,
+    CoreProtect
+}
+
+impl Default for SourceKind {
+    fn default() -> SourceKind {
+        SourceKind::Prism
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LegacyDatabasesConfig {
+    pub prism: Option<LegacyDatabaseConfig>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LegacyDatabaseConfig {
+    pub url: String,
+    pub pool_size: Option<usize>
+}
+
+
+/// The Minecraft servers served by this instance, keyed by ID. If there are none, the top-level
+/// `source` and `areas` sections describe a single server, with the `default` ID.
+#[derive(Serialize, Deserialize)]
+pub struct ServersConfig {
+    #[serde(default)]
+    pub servers: HashMap<String, ServerConfig>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ServerConfig {
+    pub name: Option<String>,
+    #[serde(default)]
+    pub source: SourceConfigInner,
+    #[serde(default)]
+    pub areas: HashMap<String, ConfigAreaEntry>,
+    pub areas_overlay: Option<PathBuf>
+}
+
+
+#[derive(Serialize, Deserialize)]
+pub struct SummaryConfig {
+    #[serde(default)]
+    pub summary: SummaryConfigInner
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SummaryConfigInner {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_summary_directory")]
+    pub directory: PathBuf,
+    #[serde(default = "default_summary_interval")]
+    pub interval: u64,
+    #[serde(default = "default_summary_batch_size")]
+    pub batch_size: usize
+}
+
+impl Default for SummaryConfigInner {
+    fn default() -> SummaryConfigInner {
+        SummaryConfigInner {
+            enabled: false,
+            directory: default_summary_directory(),
+            interval: default_summary_interval(),
+            batch_size: default_summary_batch_size()
+        }
+    }
+}
+
+fn default_summary_directory() -> PathBuf {
+    PathBuf::from("../summaries")
+}
+
+fn default_summary_interval() -> u64 {
+    60
+}
+
+fn default_summary_batch_size() -> usize {
+    10000
+}
+
+
+#[derive(Serialize, Deserialize)]
+pub struct AlertsConfig {
+    #[serde(default)]
+    pub alerts: AlertsConfigInner
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AlertsConfigInner {
+    #[serde(default = "default_alerts_interval")]
+    pub interval: u64,
+    pub webhook: Option<String>,
+    #[serde(default)]
+    pub rules: HashMap<String, AlertRuleConfig>
+}
+
+impl Default for AlertsConfigInner {
+    fn default() -> AlertsConfigInner {
+        AlertsConfigInner {
+            interval: default_alerts_interval(),
+            webhook: None,
+            rules: HashMap::new()
+        }
+    }
+}
+
+/// An alert rule, firing for every player (or area) whose ratio over the `window` crosses
+/// the `below` or `above` threshold.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AlertRuleConfig {
+    pub description: Option<String>,
+    pub server: Option<String>,
+    #[serde(default)]
+    pub areas: Vec<String>,
+    pub material: Option<String>,
+    #[serde(default = "default_alert_window")]
+    pub window: String,
+    #[serde(default)]
+    pub per: AlertScope,
+    #[serde(default)]
+    pub metric: AlertMetric,
+    pub below: Option<f64>,
+    pub above: Option<f64>,
+    pub webhook: Option<String>
+}
+
+/// What an alert rule compares to its thresholds: the ratio of each player, of each area, or
+/// the total ratio.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertScope {
+    Player,
+    Area,
+    Total
+}
+
+impl Default for AlertScope {
+    fn default() -> AlertScope {
+        AlertScope::Player
+    }
+}
+
+/// Whether an alert rule compares the net amount of items, or their value (see `item_values`).
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertMetric {
+    Ratio,
+    Value
+}
+
+impl Default for AlertMetric {
+    fn default() -> AlertMetric {
+        AlertMetric::Ratio
+    }
+}
+
+fn default_alerts_interval() -> u64 {
+    300
+}
+
+fn default_alert_window() -> String {
+    String::from("24h")
+}
+
+
+#[derive(Serialize, Deserialize)]
+pub struct DigestConfig {
+    #[serde(default)]
+    pub digest: DigestConfigInner
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DigestConfigInner {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_digest_directory")]
+    pub directory: PathBuf,
+    pub templates: Option<PathBuf>,
+    #[serde(default = "default_digest_day")]
+    pub day: String,
+    #[serde(default = "default_digest_hour")]
+    pub hour: u32,
+    #[serde(default)]
+    pub areas: Vec<String>,
+    #[serde(default = "default_digest_limit")]
+    pub limit: usize,
+    pub locale: Option<String>,
+    pub webhook: Option<String>
+}
+
+impl Default for DigestConfigInner {
+    fn default() -> DigestConfigInner {
+        DigestConfigInner {
+            enabled: false,
+            directory: default_digest_directory(),
+            templates: None,
+            day: default_digest_day(),
+            hour: default_digest_hour(),
+            areas: vec![],
+            limit: default_digest_limit(),
+            locale: None,
+            webhook: None
+        }
+    }
+}
+
+fn default_digest_directory() -> PathBuf {
+    PathBuf::from("../digests")
+}
+
+fn default_digest_day() -> String {
+    String::from("sunday")
+}
+
+fn default_digest_hour() -> u32 {
+    18
+}
+
+fn default_digest_limit() -> usize {
+    10
+}
+
+
+#[derive(Serialize, Deserialize)]
+pub struct MetricsConfig {
+    #[serde(default)]
+    pub metrics: MetricsConfigInner
+}
+
+/// With `area_flows`, the net flow of every area is exposed in the metrics, in total and for
+/// each of the listed `materials`. It is computed every `interval` seconds.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MetricsConfigInner {
+    #[serde(default)]
+    pub area_flows: bool,
+    #[serde(default)]
+    pub materials: Vec<String>,
+    #[serde(default = "default_metrics_interval")]
+    pub interval: u64
+}
+
+impl Default for MetricsConfigInner {
+    fn default() -> MetricsConfigInner {
+        MetricsConfigInner {
+            area_flows: false,
+            materials: vec![],
+            interval: default_metrics_interval()
+        }
+    }
+}
+
+fn default_metrics_interval() -> u64 {
+    300
+}
+
+
 #[derive(Serialize, Deserialize)]
 pub struct TranslationsConfig {
     pub minecraft_translations: Option<TranslationsConfigInner>
@@ -40,3 +451,77 @@ pub struct TranslationsConfigInner {
     pub directory: PathBuf,
     pub default_locale: String
 }
+
+
+#[derive(Serialize, Deserialize)]
+pub struct ItemValuesConfig {
+    pub item_values: Option<ItemValuesConfigInner>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ItemValuesConfigInner {
+    #[serde(default = "default_item_value")]
+    pub default: f64,
+    #[serde(default)]
+    pub materials: HashMap<String, f64>
+}
+
+fn default_item_value() -> f64 {
+    1.0
+}
+
+
+#[derive(Serialize, Deserialize)]
+pub struct PlayersConfig {
+    #[serde(default)]
+    pub players: PlayersConfigInner
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PlayersConfigInner {
+    #[serde(default = "default_players_exclude")]
+    pub exclude: Vec<String>,
+    #[serde(default = "default_players_exclude_patterns")]
+    pub exclude_patterns: Vec<String>,
+    #[serde(default)]
+    pub uuid_versions: Vec<usize>
+}
+
+impl Default for PlayersConfigInner {
+    fn default() -> PlayersConfigInner {
+        PlayersConfigInner {
+            exclude: default_players_exclude(),
+            exclude_patterns: default_players_exclude_patterns(),
+            uuid_versions: vec![]
+        }
+    }
+}
+
+/// The entities and causes Prism records as players.
+fn default_players_exclude() -> Vec<String> {
+    vec![
+        "Piston", "custom", "zombie", "skeleton", "Lava",
+        "dispenser", "beehive", "Environment", "suffocation",
+        "mount", "spawner", "fall", "water", "player", "cramming",
+        "breeding", "creeper", "guardian", "drowning", "drowned",
+        "fire", "piglin", "unknown", "default", "tnt", "witch",
+        "villager", "patrol", "lightning", "shulker", "pillager",
+        "dryout", "egg", "wither_skeleton", "ocelot", "fireball",
+        "infection", "player_unleash", "holder_gone", "blaze",
+        "enderman", "spectral_arrow", "piglin_brute", "hoglin",
+        "strider", "vex", "vindicator", "raid", "wolf", "stray",
+        "husk", "distance", "turtle", "wither", "zombie_villager",
+        "wandering_trader", "arrow", "cured", "void", "trap",
+        "jockey", "spider", "snowman", "starvation", "sheep", "cow",
+        "trader_llama", "fox", "magma_cube", "horse", "projectile",
+        "rabbit", "parrot", "donkey", "cat", "skeleton_horse",
+        "chicken", "zombified_piglin", "evoker", "ravager", "ghast",
+        "endermite"
+    ].into_iter().map(String::from).collect()
+}
+
+/// Namespaced entries and entries with spaces are never players, and CoreProtect prefixes
+/// non-player entries with `#`.
+fn default_players_exclude_patterns() -> Vec<String> {
+    vec!["*:*", "* *", "#*"].into_iter().map(String::from).collect()
+}
@@ -1,143 +1,650 @@
+use cached::Cached;
 use cached::proc_macro::cached;
 use itertools::Itertools;
-use mysql::prelude::*;
-use mysql::{Conn, Error};
+use rocket_contrib::uuid::Uuid as RocketUuid;
 use serde::Serialize;
 use uuid::Uuid;
 
 use crate::area::{Area, cache_key_for_vec_areas};
-use crate::params::Uuids;
+use crate::params::{Bucket, GroupBy, TimeBound, Uuids};
+use crate::players::PlayerExclusions;
+use crate::query::Filters;
 use crate::locales::MinecraftLocale;
-use std::sync::Arc;
+use crate::source::{DataError, DataSource, FlowRow, Grouping, TransactionRow};
+use crate::summary::Summary;
+use crate::values::ItemValues;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::ops::AddAssign;
+use std::sync::{Arc, Mutex, MutexGuard};
 
 
 #[derive(Serialize, Debug, Clone)]
 pub struct Player {
     pub name: String,
-    pub uuid: Uuid
+    pub uuid: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_action: Option<i64>,
+
+    /// In debug mode, the rule that excluded this entry from the players list, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub excluded_by: Option<String>
 }
 
+/// Lists the players who acted recently, excluding non-player entries according to the
+/// configured rules. In debug mode, excluded entries are kept, with the rule excluding them.
 #[cached(
     size=128, time=60,
     result = true,
     key = "String",
-    convert = r#"{ filter.clone() }"#
+    convert = r#"{ cache_key(&[&server, &filter, &debug]) }"#
 )]
-pub fn query_recent_players(c: &mut Conn, filter: String) -> Result<Vec<Player>, Error> {
-    let stmt = c.prep("
-        SELECT
-            p.player AS name,
-            HEX(p.player_uuid) AS uuid,
-            COALESCE((
-               SELECT epoch
-               FROM prism_data d
-               WHERE d.player_id = p.player_id
-               ORDER BY epoch DESC
-               LIMIT 1
-            ), 0) AS last_action
-        FROM prism_players p
-        WHERE
-            p.player LIKE :player
-            -- We try to exclude non-player entries
-            AND NOT p.player LIKE '%:%'
-            AND NOT p.player LIKE '% %'
-            AND p.player NOT IN (
-                'Piston', 'custom', 'zombie', 'skeleton', 'Lava',
-                'dispenser', 'beehive', 'Environment', 'suffocation',
-                'mount', 'spawner', 'fall', 'water', 'player', 'cramming',
-                'breeding', 'creeper', 'guardian', 'drowning', 'drowned',
-                'fire', 'piglin', 'unknown', 'default', 'tnt', 'witch',
-                'villager', 'patrol', 'lightning', 'shulker', 'pillager',
-                'dryout', 'egg', 'wither_skeleton', 'ocelot', 'fireball',
-                'infection', 'player_unleash', 'holder_gone', 'blaze',
-                'enderman', 'spectral_arrow', 'piglin_brute', 'hoglin',
-                'strider', 'vex', 'vindicator', 'raid', 'wolf', 'stray',
-                'husk', 'distance', 'turtle', 'wither', 'zombie_villager',
-                'wandering_trader', 'arrow', 'cured', 'void', 'trap',
-                'jockey', 'spider', 'snowman', 'starvation', 'sheep', 'cow',
-                'trader_llama', 'fox', 'magma_cube', 'horse', 'projectile',
-                'rabbit', 'parrot', 'donkey', 'cat', 'skeleton_horse',
-                'chicken', 'zombified_piglin', 'evoker', 'ravager', 'ghast',
-                'endermite'
-            )
-        ORDER BY last_action DESC
-        LIMIT 20
-    ")?;
-    let player = format!("%{}%", filter);
-
-    c.exec_map(
-        stmt, params! { player },
-        |(name, uuid, _): (String, String, u64)| Player {
-            name,
-            uuid: Uuid::parse_str(uuid.as_str()).unwrap_or(Uuid::nil())
-        }
-    )
+pub fn query_recent_players(source: &dyn DataSource, server: &str, filter: String, exclusions: PlayerExclusions, debug: bool) -> Result<Vec<Player>, DataError> {
+    let players = source.players(&filter, Some(&exclusions).filter(|_| !debug), 20)?;
+
+    Ok(players.into_iter()
+        .map(|player| Player {
+            excluded_by: match debug {
+                true => exclusions.rule_for(&player.name, &player.uuid),
+                false => None
+            },
+            ..player
+        })
+        .collect())
+}
+
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PlayerProfile {
+    pub name: String,
+    pub uuid: Uuid,
+    pub first_action: Option<i64>,
+    pub last_action: Option<i64>,
+
+    /// The number of records of this player, per action (e.g. `block-break`).
+    pub actions: BTreeMap<String, u64>,
+
+    /// The container activity of this player in each area they used, keyed by area ID.
+    pub areas: BTreeMap<String, AreaActivity>,
+
+    /// The worlds this player was active in, most recent first.
+    pub worlds: Vec<WorldActivity>
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct AreaActivity {
+    pub name: String,
+    #[serde(flatten)]
+    pub flow: Flow,
+    pub first_action: i64,
+    pub last_action: i64
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct WorldActivity {
+    pub world: String,
+    pub actions: u64,
+    pub first_action: i64,
+    pub last_action: i64
+}
+
+/// Builds the profile of a player, with their container activity in the given areas. Returns
+/// `None` if the player is unknown to the data source.
+#[cached(
+    size=128, time=60,
+    result = true,
+    key = "String",
+    convert = r#"{ cache_key(&[&server, &uuid, &cache_key_for_vec_areas(&areas)]) }"#
+)]
+pub fn query_player_profile(source: &dyn DataSource, server: &str, uuid: RocketUuid, areas: Vec<Area>) -> Result<Option<PlayerProfile>, DataError> {
+    let activity = match source.player_activity(&uuid)? {
+        Some(activity) => activity,
+        None => return Ok(None)
+    };
+
+    let filters = Filters::new(areas, Uuids { uuids: vec![uuid] }, TimeBound::unbounded(), TimeBound::unbounded());
+    let mut areas_activity: BTreeMap<String, AreaActivity> = BTreeMap::new();
+
+    for row in source.flows(&filters, Grouping::Area)? {
+        let area_activity = areas_activity.entry(row.group.clone()).or_insert_with(|| AreaActivity {
+            name: filters.areas.iter()
+                .find(|area| area.id == row.group)
+                .map(|area| area.name.clone())
+                .unwrap_or_default(),
+            flow: Flow::default(),
+            first_action: row.first_action,
+            last_action: row.last_action
+        });
+
+        area_activity.flow += row.flow;
+        area_activity.first_action = area_activity.first_action.min(row.first_action);
+        area_activity.last_action = area_activity.last_action.max(row.last_action);
+    }
+
+    let actions = activity.actions;
+
+    Ok(Some(PlayerProfile {
+        name: activity.name,
+        uuid: *uuid,
+        first_action: actions.iter().map(|(_, _, first_action, _)| *first_action).min(),
+        last_action: actions.iter().map(|(_, _, _, last_action)| *last_action).max(),
+        actions: actions.into_iter().map(|(action, count, _, _)| (action, count)).collect(),
+        areas: areas_activity,
+        worlds: activity.worlds
+    }))
 }
 
 
 #[derive(Serialize, Debug, Clone)]
 pub struct Ratios {
-    pub global: i64,
+    #[serde(flatten)]
+    pub totals: Totals,
+    pub detail: Vec<Ratio>,
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+
+    /// With `group_by=player`, the ratios of each player, keyed by UUID.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub players: Option<BTreeMap<String, RatiosBreakdown>>,
+
+    /// With `group_by=area`, the ratios in each area, keyed by area ID.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub areas: Option<BTreeMap<String, RatiosBreakdown>>,
+
+    /// Whether the ratios come from the summary store rather than from the data source.
+    pub summarized: bool
+}
+
+/// The ratios of one group (e.g. one player or one area) when `/ratios` results are broken down.
+#[derive(Serialize, Debug, Clone)]
+pub struct RatiosBreakdown {
+    pub name: String,
+    #[serde(flatten)]
+    pub totals: Totals,
     pub detail: Vec<Ratio>
 }
 
+/// The totals of a list of ratios. `global` is the net flow of all items.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Totals {
+    pub global: i64,
+    pub value: f64,
+    pub inserted: i64,
+    pub removed: i64,
+    pub insertions: i64,
+    pub removals: i64
+}
+
+impl Totals {
+    fn from_ratios(ratios: &Vec<Ratio>) -> Self {
+        Totals {
+            global: ratios.iter().map(|ratio| ratio.flow.ratio).sum(),
+            value: ratios.iter().map(|ratio| ratio.value).sum(),
+            inserted: ratios.iter().map(|ratio| ratio.flow.inserted).sum(),
+            removed: ratios.iter().map(|ratio| ratio.flow.removed).sum(),
+            insertions: ratios.iter().map(|ratio| ratio.flow.insertions).sum(),
+            removals: ratios.iter().map(|ratio| ratio.flow.removals).sum()
+        }
+    }
+}
+
 #[derive(Serialize, Debug, Clone)]
 pub struct Ratio {
     pub id: String,
     pub display_name: String,
-    pub ratio: i64
+    #[serde(flatten)]
+    pub flow: Flow,
+
+    /// The ratio weighted by the configured value of the item.
+    pub value: f64
+}
+
+impl Ratio {
+    fn from_material(material: String, flow: Flow, locale: &MinecraftLocale, values: &ItemValues) -> Self {
+        Ratio {
+            id: if material.contains(":") { material.clone() } else { format!("minecraft:{}", material) },
+            value: flow.ratio as f64 * values.weight_of(&material),
+            display_name: locale.translate(material),
+            flow
+        }
+    }
+}
+
+/// The movements of items: the net flow (`ratio`, positive if more items were inserted than
+/// removed), the gross amounts of items `inserted` and `removed`, and the number of `insertions`
+/// and `removals` transactions. A null ratio with high gross amounts means heavy churn.
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+pub struct Flow {
+    pub ratio: i64,
+    pub inserted: i64,
+    pub removed: i64,
+    pub insertions: i64,
+    pub removals: i64
+}
+
+impl AddAssign for Flow {
+    fn add_assign(&mut self, other: Self) {
+        self.ratio += other.ratio;
+        self.inserted += other.inserted;
+        self.removed += other.removed;
+        self.insertions += other.insertions;
+        self.removals += other.removals;
+    }
+}
+
+/// From a list of materials and their flow, computes the totals and the sorted detail.
+fn collect_ratios<I: IntoIterator<Item=(String, Flow)>>(materials: I, locale: &MinecraftLocale, values: &ItemValues) -> (Totals, Vec<Ratio>) {
+    let mut ratios: Vec<Ratio> = materials.into_iter()
+        .map(|(material, flow)| Ratio::from_material(material, flow, locale, values))
+        .collect();
+
+    ratios.sort_by_key(|ratio| -ratio.flow.ratio);
+
+    (Totals::from_ratios(&ratios), ratios)
 }
 
 #[cached(
     size=128, time=600,
     result = true,
     key = "String",
-    convert = r#"{ format!("{}{}{:?}", cache_key_for_vec_areas(&areas), players, (*locale).file) }"#
+    convert = r#"{ cache_key(&[&server, &filters, &format!("{:?}", group_by), &format!("{:?}", (*locale).file)]) }"#
 )]
-pub fn query_ratios(c: &mut Conn, areas: Vec<Area>, players: Uuids, locale: Arc<MinecraftLocale>) -> Result<Ratios, Error> {
-    let areas_where_clause: String = areas
-        .iter()
-        .map(|a| format!("({})", a.as_sql()))
-        .intersperse(String::from(" OR "))
+pub fn query_ratios(source: &dyn DataSource, server: &str, filters: Filters, group_by: GroupBy, locale: Arc<MinecraftLocale>, values: ItemValues) -> Result<Ratios, DataError> {
+    compute_ratios(source, filters, group_by, &locale, &values)
+}
+
+/// Computes the same ratios as [`query_ratios`], bypassing the cache. Relative time bounds are
+/// part of the cache key as written, so callers needing up-to-date results must use this one.
+pub fn compute_ratios(source: &dyn DataSource, filters: Filters, group_by: GroupBy, locale: &MinecraftLocale, values: &ItemValues) -> Result<Ratios, DataError> {
+    let rows = source.flows(&filters, group_by.into())?;
+    Ok(build_ratios(rows, filters, group_by, locale, values, false))
+}
+
+/// Computes the same ratios as [`query_ratios`] from the summary store. Time bounds are rounded
+/// to whole days (see [`Summary`]).
+///
+/// Results are not cached, as the summary store is local and fast.
+pub fn query_summary_ratios(summary: &Summary, mut filters: Filters, group_by: GroupBy, locale: Arc<MinecraftLocale>, values: ItemValues) -> Result<Ratios, DataError> {
+    filters.since = filters.since.round_to_day(false);
+    filters.until = filters.until.round_to_day(true);
+
+    let rows = summary.flows(&filters, group_by.into())?;
+    Ok(build_ratios(rows, filters, group_by, &locale, &values, true))
+}
+
+/// Builds the ratios from the flows of each group and material, sorted by group.
+fn build_ratios(rows: Vec<FlowRow>, filters: Filters, group_by: GroupBy, locale: &MinecraftLocale, values: &ItemValues, summarized: bool) -> Ratios {
+    let rows: Vec<(String, String, String, Flow)> = rows.into_iter()
+        .map(|row| (row.group, row.name, row.material, row.flow))
         .collect();
-    let players_where_clause = players.as_sql();
-    let sql = format!(
-        "
-        SELECT material, SUM(amount_diff) AS ratio
-        FROM (
-             SELECT
-                    b.material AS material,
-                    IF(action = 'item-insert', 1, -1) * JSON_EXTRACT(e.data, '$.amt') AS amount_diff
-            FROM prism_data d
-            LEFT JOIN prism_actions a ON a.action_id = d.action_id
-            LEFT JOIN prism_players p ON p.player_id = d.player_id
-            LEFT JOIN prism_worlds w ON w.world_id = d.world_id
-            LEFT JOIN prism_id_map b ON b.block_id = d.block_id
-            LEFT JOIN prism_data_extra e ON e.data_id = d.id
-            WHERE a.action IN ('item-insert', 'item-remove')
-                AND ({})
-                AND ({})
-        ) history
-        GROUP BY material
-        ORDER BY ratio;
-        ",
-        areas_where_clause,
-        players_where_clause
-    );
-
-    let mut ratios: Vec<Ratio> = c.query_map(
-        sql,
-        |(material, ratio): (String, i64)| Ratio {
-            id: if material.contains(":") { material.clone() } else { format!("minecraft:{}", material) },
-            display_name: locale.translate(material),
-            ratio
-        }
-    )?.into_iter().collect();
 
-    ratios.sort_by_key(|ratio| -ratio.ratio);
+    let mut totals: HashMap<String, Flow> = HashMap::new();
+    for (_, _, material, flow) in rows.iter() {
+        *totals.entry(material.clone()).or_default() += *flow;
+    }
+
+    let breakdown: Option<BTreeMap<String, RatiosBreakdown>> = match group_by {
+        GroupBy::None => None,
+        _ => Some(
+            rows.into_iter()
+                .group_by(|(group_key, _, _, _)| group_key.clone())
+                .into_iter()
+                .map(|(group_key, rows)| {
+                    let mut rows = rows.peekable();
+                    let name = match group_by {
+                        // Areas names are not stored in the database but in the configuration.
+                        GroupBy::Area => filters.areas.iter()
+                            .find(|area| area.id == group_key)
+                            .map(|area| area.name.clone())
+                            .unwrap_or_default(),
+                        _ => rows.peek().map(|(_, name, _, _)| name.clone()).unwrap_or_default()
+                    };
+                    let (totals, detail) = collect_ratios(rows.map(|(_, _, material, flow)| (material, flow)), locale, values);
+
+                    (breakdown_key(group_by, group_key), RatiosBreakdown { name, totals, detail })
+                })
+                .collect()
+        )
+    };
+
+    let (totals, detail) = collect_ratios(totals, locale, values);
+    let (players_breakdown, areas_breakdown) = match group_by {
+        GroupBy::Player => (breakdown, None),
+        GroupBy::Area => (None, breakdown),
+        GroupBy::None => (None, None)
+    };
+
+    Ratios {
+        totals,
+        detail,
+        since: filters.since.epoch,
+        until: filters.until.epoch,
+        players: players_breakdown,
+        areas: areas_breakdown,
+        summarized
+    }
+}
+
+/// Normalizes the raw group key returned by the database into the key used in the response.
+fn breakdown_key(group_by: GroupBy, group_key: String) -> String {
+    match group_by {
+        GroupBy::Player => Uuid::parse_str(group_key.as_str())
+            .unwrap_or(Uuid::nil())
+            .to_hyphenated()
+            .to_string(),
+        _ => group_key
+    }
+}
+
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Timeline {
+    pub bucket: Bucket,
+    pub buckets: Vec<TimelineBucket>,
+    pub since: Option<i64>,
+    pub until: Option<i64>
+}
+
+/// The net flow of items during one bucket of a [`Timeline`]. `start` is the timestamp of the
+/// beginning of the bucket. Buckets without any transaction are omitted.
+#[derive(Serialize, Debug, Clone)]
+pub struct TimelineBucket {
+    pub start: i64,
+    #[serde(flatten)]
+    pub totals: Totals,
+    pub detail: Vec<Ratio>
+}
+
+#[cached(
+    size=128, time=600,
+    result = true,
+    key = "String",
+    convert = r#"{ cache_key(&[&server, &filters, &format!("{:?}", bucket), &format!("{:?}", (*locale).file)]) }"#
+)]
+pub fn query_timeline(source: &dyn DataSource, server: &str, filters: Filters, bucket: Bucket, locale: Arc<MinecraftLocale>, values: ItemValues) -> Result<Timeline, DataError> {
+    let rows: Vec<(i64, String, Flow)> = source.flows(&filters, Grouping::Bucket(bucket))?
+        .into_iter()
+        .map(|row| (row.group.parse().unwrap_or_default(), row.material, row.flow))
+        .collect();
+
+    let buckets = rows.into_iter()
+        .group_by(|(start, _, _)| *start)
+        .into_iter()
+        .map(|(start, rows)| {
+            let (totals, detail) = collect_ratios(rows.map(|(_, material, flow)| (material, flow)), &locale, &values);
+            TimelineBucket { start, totals, detail }
+        })
+        .collect();
 
-    Ok(Ratios {
-        global: ratios.iter().map(|ratio| ratio.ratio).sum(),
-        detail: ratios
+    Ok(Timeline {
+        bucket,
+        buckets,
+        since: filters.since.epoch,
+        until: filters.until.epoch
     })
 }
+
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Leaderboard {
+    pub area: Area,
+    pub givers: Vec<LeaderboardEntry>,
+    pub takers: Vec<LeaderboardEntry>,
+    pub since: Option<i64>,
+    pub until: Option<i64>
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct LeaderboardEntry {
+    pub rank: usize,
+    pub name: String,
+    pub uuid: Uuid,
+    #[serde(flatten)]
+    pub flow: Flow
+}
+
+#[cached(
+    size=128, time=600,
+    result = true,
+    key = "String",
+    convert = r#"{ cache_key(&[&server, &area.id, &since, &until, &limit]) }"#
+)]
+pub fn query_leaderboard(source: &dyn DataSource, server: &str, area: Area, since: TimeBound, until: TimeBound, limit: usize, exclusions: PlayerExclusions) -> Result<Leaderboard, DataError> {
+    let mut filters = Filters::new(vec![area.clone()], Uuids::any(), since, until);
+    filters.exclusions = Some(exclusions);
+
+    // Flows are per player and material; players are then ranked by their overall ratio.
+    let entries: Vec<(String, String, Flow)> = source.flows(&filters, Grouping::Player)?
+        .into_iter()
+        .group_by(|row| row.group.clone())
+        .into_iter()
+        .map(|(uuid, rows)| {
+            let mut name = String::new();
+            let mut flow = Flow::default();
+            for row in rows {
+                name = row.name;
+                flow += row.flow;
+            }
+            (uuid, name, flow)
+        })
+        .filter(|(_, _, flow)| flow.ratio != 0)
+        .sorted_by_key(|(_, _, flow)| -flow.ratio)
+        .collect();
+
+    let entry = |rank: usize, (uuid, name, flow): &(String, String, Flow)| LeaderboardEntry {
+        rank: rank + 1,
+        name: name.clone(),
+        uuid: Uuid::parse_str(uuid.as_str()).unwrap_or(Uuid::nil()),
+        flow: *flow
+    };
+
+    Ok(Leaderboard {
+        area,
+        givers: entries.iter()
+            .filter(|(_, _, flow)| flow.ratio > 0)
+            .take(limit)
+            .enumerate()
+            .map(|(rank, e)| entry(rank, e))
+            .collect(),
+        takers: entries.iter()
+            .rev()
+            .filter(|(_, _, flow)| flow.ratio < 0)
+            .take(limit)
+            .enumerate()
+            .map(|(rank, e)| entry(rank, e))
+            .collect(),
+        since: filters.since.epoch,
+        until: filters.until.epoch
+    })
+}
+
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Transactions {
+    pub transactions: Vec<Transaction>,
+
+    /// The cursor to use to get the next page, if there is one.
+    pub next_cursor: Option<u64>
+}
+
+/// A single container transaction, as recorded by the data source.
+#[derive(Serialize, Debug, Clone)]
+pub struct Transaction {
+    pub id: u64,
+    pub epoch: i64,
+    pub action: String,
+    pub material: String,
+    pub display_name: String,
+    pub amount: i64,
+    pub x: i64,
+    pub y: i64,
+    pub z: i64,
+    pub world: String,
+    pub area: String,
+    pub player: Player
+}
+
+impl Transaction {
+    fn from_row(row: TransactionRow, locale: &MinecraftLocale) -> Self {
+        Transaction {
+            id: row.id,
+            epoch: row.epoch,
+            action: row.action,
+            material: if row.material.contains(":") { row.material.clone() } else { format!("minecraft:{}", row.material) },
+            display_name: locale.translate(row.material),
+            amount: row.amount,
+            x: row.x,
+            y: row.y,
+            z: row.z,
+            world: row.world,
+            area: row.area,
+            player: Player {
+                name: row.player_name,
+                uuid: Uuid::parse_str(row.player_uuid.as_str()).unwrap_or(Uuid::nil()),
+                last_action: None,
+                excluded_by: None
+            }
+        }
+    }
+}
+
+/// Lists the transactions matching the filters, most recent first. Only transactions older than
+/// the `cursor` (a transaction ID) are returned, if given.
+///
+/// Results are not cached, as they are used as evidence and should be up-to-date.
+pub fn query_transactions(source: &dyn DataSource, filters: Filters, cursor: Option<u64>, limit: usize, locale: Arc<MinecraftLocale>) -> Result<Transactions, DataError> {
+    // We fetch one more row to know if there is a next page.
+    let mut transactions: Vec<Transaction> = source.transactions(&filters, cursor, limit + 1)?
+        .into_iter()
+        .map(|row| Transaction::from_row(row, &locale))
+        .collect();
+
+    let next_cursor = match transactions.len() > limit {
+        true => {
+            transactions.truncate(limit);
+            transactions.last().map(|transaction| transaction.id)
+        },
+        false => None
+    };
+
+    Ok(Transactions { transactions, next_cursor })
+}
+
+
+/// New transactions read when tailing the data source. `last_id` is the ID of the last record
+/// read, matching the filters or not, from which the next read should start; if `more` is set,
+/// other records were already available after it.
+#[derive(Debug, Clone)]
+pub struct NewTransactions {
+    pub transactions: Vec<Transaction>,
+    pub last_id: u64,
+    pub more: bool
+}
+
+/// Lists the transactions matching the filters recorded after the `after` transaction ID, oldest
+/// first. At most `limit` records of the data source are read.
+///
+/// Results are not cached, as they are used to follow the transactions live.
+pub fn query_new_transactions(source: &dyn DataSource, filters: &Filters, after: u64, limit: usize, locale: &MinecraftLocale) -> Result<NewTransactions, DataError> {
+    let rows = source.history(after, limit)?;
+
+    let more = rows.len() >= limit;
+    let last_id = rows.last().map(|row| row.id).unwrap_or(after);
+
+    let transactions = rows.into_iter()
+        .filter_map(|row| {
+            let area = filters.area_of(&row)?.id.clone();
+
+            Some(Transaction::from_row(TransactionRow {
+                id: row.id,
+                epoch: row.epoch,
+                action: String::from(if row.amount_diff >= 0 { "item-insert" } else { "item-remove" }),
+                material: row.material,
+                amount: row.amount_diff.abs(),
+                x: row.x,
+                y: row.y,
+                z: row.z,
+                world: row.world,
+                area,
+                player_uuid: row.player_uuid,
+                player_name: row.player_name
+            }, locale))
+        })
+        .collect();
+
+    Ok(NewTransactions { transactions, last_id, more })
+}
+
+/// The statistics of a query cache, exposed as metrics.
+#[derive(Debug, Clone)]
+pub struct CacheStats {
+    pub name: &'static str,
+    pub hits: u64,
+    pub misses: u64,
+    pub size: usize
+}
+
+/// Returns the statistics of every query cache.
+pub fn caches_stats() -> Vec<CacheStats> {
+    macro_rules! stats {
+        ($name:expr, $cache:ident) => {{
+            let cache = lock_cache(&$cache);
+            CacheStats {
+                name: $name,
+                hits: cache.cache_hits().unwrap_or(0),
+                misses: cache.cache_misses().unwrap_or(0),
+                size: cache.cache_size()
+            }
+        }}
+    }
+
+    vec![
+        stats!("recent_players", QUERY_RECENT_PLAYERS),
+        stats!("player_profile", QUERY_PLAYER_PROFILE),
+        stats!("ratios", QUERY_RATIOS),
+        stats!("timeline", QUERY_TIMELINE),
+        stats!("leaderboard", QUERY_LEADERBOARD)
+    ]
+}
+
+/// Clears the cached results depending on the areas definitions, so that changes made to areas
+/// at runtime are visible immediately.
+pub fn clear_areas_caches() {
+    lock_cache(&QUERY_PLAYER_PROFILE).cache_clear();
+    lock_cache(&QUERY_RATIOS).cache_clear();
+    lock_cache(&QUERY_TIMELINE).cache_clear();
+    lock_cache(&QUERY_LEADERBOARD).cache_clear();
+}
+
+/// Builds a cache key from the parameters of a query, joined with `|` like the formatted version
+/// of [`Filters`], so that different parameters can't give the same key.
+fn cache_key(parts: &[&dyn fmt::Display]) -> String {
+    parts.iter().map(|part| part.to_string()).join("|")
+}
+
+// A cache is only changed through its own methods, each leaving it consistent, so it's safe to
+// accept poisoned mutexes.
+fn lock_cache<T>(cache: &Mutex<T>) -> MutexGuard<'_, T> {
+    match cache.lock() {
+        Ok(cache) => cache,
+        Err(poisoned) => poisoned.into_inner()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_keys_are_distinct() {
+        let key = |area: &str, since: i64, until: i64, limit: usize|
+            cache_key(&[&"default", &area, &TimeBound::at(since), &TimeBound::at(until), &limit]);
+
+        assert_ne!(key("a", 1, 23, 10), key("a", 12, 3, 10));
+        assert_ne!(key("a1", 2, 3, 4), key("a", 12, 3, 4));
+        assert_ne!(key("a", 1, 2, 34), key("a", 1, 23, 4));
+        assert_eq!(key("a", 1, 2, 10), key("a", 1, 2, 10));
+        assert_eq!(cache_key(&[&"default", &"diamond", &true]), "default|diamond|true");
+    }
+}
@@ -0,0 +1,326 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use chrono::{DateTime, Datelike, TimeZone, Utc, Weekday};
+use itertools::Itertools;
+use serde::Serialize;
+use tera::{Context, Tera, Value};
+use uuid::Uuid;
+
+use crate::area::Area;
+use crate::config::DigestConfigInner;
+use crate::database::{LeaderboardEntry, Ratio, query_leaderboard, query_ratios};
+use crate::locales::MinecraftLocale;
+use crate::params::{AreasIds, GroupBy, TimeBound, Uuids};
+use crate::players::PlayerExclusions;
+use crate::query::Filters;
+use crate::servers::{Server, Servers};
+use crate::source::Grouping;
+use crate::values::ItemValues;
+use crate::webhook::post_message;
+
+
+const MARKDOWN_TEMPLATE: &str = "digest.md";
+const HTML_TEMPLATE: &str = "digest.html";
+
+/// How often the scheduler checks whether digests are due.
+const CHECK_INTERVAL: Duration = Duration::from_secs(600);
+
+/// The data of a digest, as given to the templates. Dates are formatted as `YYYY-MM-DD` (UTC).
+#[derive(Serialize, Debug, Clone)]
+struct Digest {
+    server: String,
+    area: String,
+    area_id: String,
+    since: String,
+    until: String,
+    givers: Vec<LeaderboardEntry>,
+    takers: Vec<LeaderboardEntry>,
+    movements: Vec<Ratio>,
+    new_players: Vec<NewPlayer>
+}
+
+/// A player whose first transaction in an area happened during the week of a digest.
+#[derive(Serialize, Debug, Clone)]
+struct NewPlayer {
+    name: String,
+    uuid: Uuid,
+    first_seen: String
+}
+
+/// Generates a weekly digest of every area: top contributors and takers, biggest material
+/// movements and new players. Digests are rendered to Markdown and HTML files, stored as
+/// `<directory>/<server>/<area>/<date>.{md,html}`, and the Markdown version is optionally
+/// posted to a webhook.
+///
+/// A background task generates the digests of the last week when they are due. As existing
+/// digests are not generated again, a digest missed while the server was down is generated at
+/// startup.
+pub(crate) struct Digests {
+    config: DigestConfigInner,
+    day: Weekday,
+    templates: Tera,
+    servers: Servers,
+    locale: Arc<MinecraftLocale>,
+    values: ItemValues,
+    exclusions: PlayerExclusions
+}
+
+impl Digests {
+    /// Validates the schedule and the areas, and loads the templates. Each configured area (or
+    /// group) must exist on at least one server.
+    pub fn load(config: DigestConfigInner, servers: Servers, locale: Arc<MinecraftLocale>, values: ItemValues, exclusions: PlayerExclusions) -> Result<Self, Vec<String>> {
+        let mut errors = vec![];
+
+        let day = Weekday::from_str(&config.day).unwrap_or_else(|_| {
+            errors.push(format!("invalid day {}: expected a day of the week, e.g. `sunday`", config.day));
+            Weekday::Sun
+        });
+
+        if config.hour > 23 {
+            errors.push(format!("invalid hour {}: expected an hour between 0 and 23", config.hour));
+        }
+
+        for id in config.areas.iter() {
+            if servers.iter().all(|server| server.areas.get().expand(id).is_empty()) {
+                errors.push(format!("unknown area {}: no server has an area or group with this ID", id));
+            }
+        }
+
+        let templates = match load_templates(config.templates.as_deref()) {
+            Ok(templates) => templates,
+            Err(error) => {
+                errors.push(error);
+                Tera::default()
+            }
+        };
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        Ok(Digests { config, day, templates, servers, locale, values, exclusions })
+    }
+
+    /// Starts the background task generating the digests when they are due.
+    pub fn start(self) {
+        thread::spawn(move || loop {
+            self.generate_due(Utc::now());
+            thread::sleep(CHECK_INTERVAL);
+        });
+    }
+
+    /// Generates the digests of the week ending at the last scheduled time before `now`, unless
+    /// they already exist.
+    fn generate_due(&self, now: DateTime<Utc>) {
+        let until = self.last_schedule(now);
+        let since = until - chrono::Duration::weeks(1);
+        let date = until.format("%Y-%m-%d").to_string();
+
+        for server in self.servers.iter() {
+            let areas = server.areas.get().filter(AreasIds::list(self.config.areas.clone()));
+
+            for (id, area) in areas.areas.iter().sorted_by(|(a, _), (b, _)| a.cmp(b)) {
+                let directory = self.config.directory.join(path_segment(&server.id)).join(path_segment(id));
+
+                if directory.join(format!("{}.md", date)).exists() {
+                    continue;
+                }
+
+                let markdown = self.generate(server, area, since, until)
+                    .and_then(|digest| self.write(&directory, &date, &digest));
+
+                match markdown {
+                    Ok(markdown) => if let Some(webhook) = &self.config.webhook {
+                        if let Err(error) = post_message(webhook, &markdown) {
+                            eprintln!("Unable to post the digest of area {} on server {}: {}", id, server.id, error);
+                        }
+                    },
+                    Err(error) => eprintln!("Unable to generate the digest of area {} on server {}: {}", id, server.id, error)
+                }
+            }
+        }
+    }
+
+    /// Returns the last scheduled time (the configured day and hour, UTC) before `now`.
+    fn last_schedule(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let mut date = now.date();
+
+        loop {
+            let scheduled = date.and_hms(self.config.hour, 0, 0);
+
+            if date.weekday() == self.day && scheduled <= now {
+                return scheduled;
+            }
+
+            date = date.pred();
+        }
+    }
+
+    fn generate(&self, server: &Server, area: &Area, since: DateTime<Utc>, until: DateTime<Utc>) -> Result<Digest, String> {
+        let source = server.source.blocking();
+        let limit = self.config.limit;
+
+        let leaderboard = query_leaderboard(source, &server.id, area.clone(), TimeBound::at(since.timestamp()), TimeBound::at(until.timestamp()), limit, self.exclusions.clone())
+            .map_err(|error| error.to_string())?;
+
+        let filters = Filters::new(vec![area.clone()], Uuids::any(), TimeBound::at(since.timestamp()), TimeBound::at(until.timestamp()));
+        let movements: Vec<Ratio> = query_ratios(source, &server.id, filters, GroupBy::None, Arc::clone(&self.locale), self.values.clone())
+            .map_err(|error| error.to_string())?
+            .detail
+            .into_iter()
+            .filter(|ratio| ratio.flow.ratio != 0)
+            .sorted_by_key(|ratio| -ratio.flow.ratio.abs())
+            .take(limit)
+            .collect();
+
+        // Players are new if their first transaction in the area happened during the week.
+        let mut filters = Filters::new(vec![area.clone()], Uuids::any(), TimeBound::unbounded(), TimeBound::at(until.timestamp()));
+        filters.exclusions = Some(self.exclusions.clone());
+
+        let mut first_seen: BTreeMap<String, (String, i64)> = BTreeMap::new();
+        for row in source.flows(&filters, Grouping::Player).map_err(|error| error.to_string())? {
+            let entry = first_seen.entry(row.group).or_insert((row.name, row.first_action));
+            entry.1 = entry.1.min(row.first_action);
+        }
+
+        let new_players = first_seen.into_iter()
+            .filter(|(_, (_, first_action))| *first_action >= since.timestamp())
+            .sorted_by_key(|(_, (_, first_action))| *first_action)
+            .map(|(uuid, (name, first_action))| NewPlayer {
+                name,
+                uuid: Uuid::parse_str(uuid.as_str()).unwrap_or(Uuid::nil()),
+                first_seen: Utc.timestamp(first_action, 0).format("%Y-%m-%d").to_string()
+            })
+            .collect();
+
+        Ok(Digest {
+            server: server.name.clone(),
+            area: area.name.clone(),
+            area_id: area.id.clone(),
+            since: since.format("%Y-%m-%d").to_string(),
+            until: until.format("%Y-%m-%d").to_string(),
+            givers: leaderboard.givers,
+            takers: leaderboard.takers,
+            movements,
+            new_players
+        })
+    }
+
+    /// Renders and writes a digest, and returns its Markdown version. The Markdown file is
+    /// written last, as its presence marks the digest as generated.
+    fn write(&self, directory: &Path, date: &str, digest: &Digest) -> Result<String, String> {
+        let context = Context::from_serialize(digest).map_err(|error| error.to_string())?;
+
+        let html = self.templates.render(HTML_TEMPLATE, &context)
+            .map_err(|error| format!("Unable to render the HTML template: {}", error))?;
+        let markdown = self.templates.render(MARKDOWN_TEMPLATE, &context)
+            .map_err(|error| format!("Unable to render the Markdown template: {}", error))?;
+
+        fs::create_dir_all(directory)
+            .and_then(|_| fs::write(directory.join(format!("{}.html", date)), &html))
+            .and_then(|_| fs::write(directory.join(format!("{}.md", date)), &markdown))
+            .map_err(|error| format!("Unable to write the digest in {:?}: {}", directory, error))?;
+
+        Ok(markdown)
+    }
+}
+
+/// Loads the templates: the default ones, overridden by the `digest.md.tera` and
+/// `digest.html.tera` files of the templates directory, if any. The HTML template is escaped;
+/// the Markdown one escapes names with the `markdown_escape` filter.
+fn load_templates(directory: Option<&Path>) -> Result<Tera, String> {
+    let defaults = [
+        (MARKDOWN_TEMPLATE, include_str!("../templates/digest.md.tera")),
+        (HTML_TEMPLATE, include_str!("../templates/digest.html.tera"))
+    ];
+
+    let mut tera = Tera::default();
+    tera.register_filter("markdown_escape", markdown_escape);
+
+    for (name, default) in defaults.iter() {
+        let path = directory.map(|directory| directory.join(format!("{}.tera", name)));
+
+        let template = match path {
+            Some(path) if path.exists() => fs::read_to_string(&path)
+                .map_err(|error| format!("Unable to read template {:?}: {}", path, error))?,
+            _ => default.to_string()
+        };
+
+        tera.add_raw_template(name, &template)
+            .map_err(|error| format!("Invalid template {}: {}", name, error))?;
+    }
+
+    Ok(tera)
+}
+
+/// A template filter escaping the Markdown special characters of a string, so that names
+/// (e.g. `__Steve__`) are displayed as is instead of being formatted.
+fn markdown_escape(value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+    match value.as_str() {
+        Some(text) => Ok(Value::String(escape_markdown(text))),
+        None => Err(tera::Error::msg(format!("markdown_escape expects a string, got {}", value)))
+    }
+}
+
+fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        if "\\`*_{}[]()<>#+-.!|~".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// Makes an ID safe to use as a path segment.
+fn path_segment(id: &str) -> String {
+    id.replace(|c: char| c == '/' || c == '\\' || c == '.', "_")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markdown_is_escaped() {
+        assert_eq!(escape_markdown("Steve"), "Steve");
+        assert_eq!(escape_markdown("__Steve__"), "\\_\\_Steve\\_\\_");
+        assert_eq!(escape_markdown("*[x](y)*"), "\\*\\[x\\]\\(y\\)\\*");
+        assert_eq!(escape_markdown("a\\b`c`"), "a\\\\b\\`c\\`");
+    }
+
+    #[test]
+    fn digest_names_are_escaped() {
+        let digest = Digest {
+            server: String::from("Survival"),
+            area: String::from("The *bank*"),
+            area_id: String::from("bank"),
+            since: String::from("2020-11-08"),
+            until: String::from("2020-11-15"),
+            givers: vec![],
+            takers: vec![],
+            movements: vec![],
+            new_players: vec![NewPlayer {
+                name: String::from("__Steve__"),
+                uuid: Uuid::nil(),
+                first_seen: String::from("2020-11-10")
+            }]
+        };
+
+        let templates = load_templates(None).unwrap();
+        let markdown = templates.render(MARKDOWN_TEMPLATE, &Context::from_serialize(&digest).unwrap()).unwrap();
+
+        assert!(markdown.starts_with("# The \\*bank\\*: weekly digest"), "{}", markdown);
+        assert!(markdown.contains("- **\\_\\_Steve\\_\\_**, first seen on 2020-11-10"), "{}", markdown);
+    }
+}
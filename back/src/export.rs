@@ -0,0 +1,414 @@
+use std::io;
+
+use bytes::Bytes;
+use futures::stream::{self, BoxStream, StreamExt};
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::{self, FromRequest, Outcome, Request};
+use rocket::response::{self, Responder, Stream};
+use rocket::response::content::Content;
+use rocket_contrib::json::Json;
+use serde::Serialize;
+use tokio::io::{StreamReader, stream_reader};
+use uuid::Uuid;
+
+use crate::audit::{AUDIT_CSV_HEADER, AuditEntry};
+use crate::database::{Flow, Leaderboard, LeaderboardEntry, Player, Ratio, Ratios, Timeline, Transaction};
+
+
+/// A response body streamed from chunks of bytes.
+pub type BodyStream = Stream<StreamReader<BoxStream<'static, io::Result<Bytes>>, Bytes>>;
+
+/// The format of a list endpoint response, requested with the `?format=` query string
+/// (`json`, `csv` or `ndjson`) or else with the `Accept` header. Defaults to JSON.
+///
+/// The guard fails on an unknown `?format=`; endpoints take it as a `Result` to answer with
+/// their own JSON error instead of Rocket's error page.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Csv,
+    Ndjson
+}
+
+impl Format {
+    fn content_type(&self) -> ContentType {
+        match self {
+            Format::Json => ContentType::JSON,
+            Format::Csv => ContentType::CSV,
+            Format::Ndjson => ContentType::new("application", "x-ndjson")
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for Format {
+    type Error = String;
+
+    async fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        if let Some(Ok(format)) = request.get_query_value::<String>("format") {
+            return match format.to_lowercase().as_str() {
+                "json" => Outcome::Success(Format::Json),
+                "csv" => Outcome::Success(Format::Csv),
+                "ndjson" => Outcome::Success(Format::Ndjson),
+                _ => Outcome::Failure((Status::BadRequest, format!("Unknown format {}", format)))
+            };
+        }
+
+        let format = match request.accept().map(|accept| accept.preferred().media_type()) {
+            Some(media) if media.top() == "text" && media.sub() == "csv" => Format::Csv,
+            Some(media) if media.top() == "application" && (media.sub() == "x-ndjson" || media.sub() == "ndjson") => Format::Ndjson,
+            _ => Format::Json
+        };
+
+        Outcome::Success(format)
+    }
+}
+
+/// A row of an exported list. Rows are serialized as is in NDJSON, one per line, and as the
+/// columns of `CSV_HEADER` in CSV.
+pub trait Row: Serialize + Send + 'static {
+    const CSV_HEADER: &'static str;
+
+    /// Formats this row as a CSV line, without the line break.
+    fn to_csv(&self) -> String;
+}
+
+/// The response of a list endpoint: the whole value in JSON, or its rows in CSV or NDJSON. Rows
+/// are queried and collected beforehand, like the JSON value; only their serialization is done
+/// as they are sent, so the serialized body is never built as a whole. Some data of the JSON
+/// value (e.g. pagination cursors) may be sent as headers with rows.
+pub enum Export<T> {
+    Json(Json<T>),
+    Rows(Format, BodyStream, Vec<Header<'static>>)
+}
+
+impl<T: Serialize> Export<T> {
+    /// Exports a value in the given format, converting it to rows with `rows` if needed.
+    pub fn of<R: Row, F: FnOnce(T) -> Vec<R>>(format: Format, value: T, rows: F) -> Self {
+        match format {
+            Format::Json => Export::Json(Json(value)),
+            _ => Export::Rows(format, stream_rows(format, rows(value)), vec![])
+        }
+    }
+
+    /// Adds a header to the response, when exporting rows.
+    pub fn with_header(self, header: Header<'static>) -> Self {
+        match self {
+            Export::Rows(format, body, mut headers) => {
+                headers.push(header);
+                Export::Rows(format, body, headers)
+            },
+            json => json
+        }
+    }
+}
+
+impl<'r, 'o: 'r, T: Serialize> Responder<'r, 'o> for Export<T> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'o> {
+        match self {
+            Export::Json(json) => json.respond_to(request),
+            Export::Rows(format, body, headers) => {
+                let mut response = Content(format.content_type(), body).respond_to(request)?;
+                for header in headers {
+                    response.set_header(header);
+                }
+                Ok(response)
+            }
+        }
+    }
+}
+
+/// Streams already collected rows in CSV (with a header line) or NDJSON, serializing each row
+/// when it is sent.
+fn stream_rows<R: Row>(format: Format, rows: Vec<R>) -> BodyStream {
+    let header = match format {
+        Format::Csv => Some(format!("{}\n", R::CSV_HEADER)),
+        _ => None
+    };
+
+    let lines = header.into_iter()
+        .chain(rows.into_iter().map(move |row| match format {
+            Format::Csv => row.to_csv() + "\n",
+            _ => serde_json::to_string(&row).unwrap_or_default() + "\n"
+        }))
+        .map(|line| Ok(Bytes::from(line)));
+
+    Stream::from(stream_reader(stream::iter(lines).boxed()))
+}
+
+/// Escapes a CSV field if needed. Fields starting like a formula (`=`, `+`, `-`, `@`) are
+/// prefixed with a quote, so that spreadsheets opening the file display them as text instead
+/// of evaluating them, as some fields (e.g. audited query strings) are user-supplied.
+pub fn csv_field(field: &str) -> String {
+    let field = match field.starts_with(|c| c == '=' || c == '+' || c == '-' || c == '@' || c == '\t' || c == '\r') {
+        true => format!("'{}", field),
+        false => field.to_string()
+    };
+
+    match field.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field
+    }
+}
+
+fn flow_csv(flow: &Flow) -> String {
+    format!("{},{},{},{},{}", flow.ratio, flow.inserted, flow.removed, flow.insertions, flow.removals)
+}
+
+
+impl Row for AuditEntry {
+    const CSV_HEADER: &'static str = AUDIT_CSV_HEADER;
+
+    fn to_csv(&self) -> String {
+        AuditEntry::to_csv(self)
+    }
+}
+
+impl Row for Player {
+    const CSV_HEADER: &'static str = "name,uuid,last_action,excluded_by";
+
+    fn to_csv(&self) -> String {
+        vec![
+            csv_field(&self.name),
+            self.uuid.to_string(),
+            self.last_action.map(|last_action| last_action.to_string()).unwrap_or_default(),
+            self.excluded_by.as_deref().map(csv_field).unwrap_or_default()
+        ].join(",")
+    }
+}
+
+impl Row for Transaction {
+    const CSV_HEADER: &'static str = "id,epoch,action,material,display_name,amount,x,y,z,world,area,player_name,player_uuid";
+
+    fn to_csv(&self) -> String {
+        vec![
+            self.id.to_string(),
+            self.epoch.to_string(),
+            csv_field(&self.action),
+            csv_field(&self.material),
+            csv_field(&self.display_name),
+            self.amount.to_string(),
+            self.x.to_string(),
+            self.y.to_string(),
+            self.z.to_string(),
+            csv_field(&self.world),
+            csv_field(&self.area),
+            csv_field(&self.player.name),
+            self.player.uuid.to_string()
+        ].join(",")
+    }
+}
+
+
+/// The ratio of one item, in one group (player or area) if ratios are broken down.
+#[derive(Serialize, Debug, Clone)]
+pub struct RatioRow {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group_name: Option<String>,
+    pub id: String,
+    pub display_name: String,
+    #[serde(flatten)]
+    pub flow: Flow,
+    pub value: f64
+}
+
+impl RatioRow {
+    fn of(group: Option<(String, String)>, ratio: Ratio) -> Self {
+        let (group, group_name) = group.map(|(id, name)| (Some(id), Some(name))).unwrap_or((None, None));
+
+        RatioRow {
+            group,
+            group_name,
+            id: ratio.id,
+            display_name: ratio.display_name,
+            flow: ratio.flow,
+            value: ratio.value
+        }
+    }
+
+    /// Lists the ratios of each item, in each group if broken down.
+    pub fn rows(ratios: Ratios) -> Vec<RatioRow> {
+        match ratios.players.or(ratios.areas) {
+            Some(groups) => groups.into_iter()
+                .flat_map(|(id, breakdown)| {
+                    let name = breakdown.name;
+                    breakdown.detail.into_iter().map(move |ratio| RatioRow::of(Some((id.clone(), name.clone())), ratio))
+                })
+                .collect(),
+            None => ratios.detail.into_iter().map(|ratio| RatioRow::of(None, ratio)).collect()
+        }
+    }
+}
+
+impl Row for RatioRow {
+    const CSV_HEADER: &'static str = "group,group_name,id,display_name,ratio,inserted,removed,insertions,removals,value";
+
+    fn to_csv(&self) -> String {
+        vec![
+            self.group.as_deref().map(csv_field).unwrap_or_default(),
+            self.group_name.as_deref().map(csv_field).unwrap_or_default(),
+            csv_field(&self.id),
+            csv_field(&self.display_name),
+            flow_csv(&self.flow),
+            self.value.to_string()
+        ].join(",")
+    }
+}
+
+/// The ratio of one item during one bucket of a timeline.
+#[derive(Serialize, Debug, Clone)]
+pub struct TimelineRow {
+    pub start: i64,
+    pub id: String,
+    pub display_name: String,
+    #[serde(flatten)]
+    pub flow: Flow,
+    pub value: f64
+}
+
+impl TimelineRow {
+    /// Lists the ratios of each item in each bucket.
+    pub fn rows(timeline: Timeline) -> Vec<TimelineRow> {
+        timeline.buckets.into_iter()
+            .flat_map(|bucket| {
+                let start = bucket.start;
+                bucket.detail.into_iter().map(move |ratio| TimelineRow {
+                    start,
+                    id: ratio.id,
+                    display_name: ratio.display_name,
+                    flow: ratio.flow,
+                    value: ratio.value
+                })
+            })
+            .collect()
+    }
+}
+
+impl Row for TimelineRow {
+    const CSV_HEADER: &'static str = "start,id,display_name,ratio,inserted,removed,insertions,removals,value";
+
+    fn to_csv(&self) -> String {
+        vec![
+            self.start.to_string(),
+            csv_field(&self.id),
+            csv_field(&self.display_name),
+            flow_csv(&self.flow),
+            self.value.to_string()
+        ].join(",")
+    }
+}
+
+/// An entry of a leaderboard; `list` is either `givers` or `takers`.
+#[derive(Serialize, Debug, Clone)]
+pub struct LeaderboardRow {
+    pub list: &'static str,
+    pub rank: usize,
+    pub name: String,
+    pub uuid: Uuid,
+    #[serde(flatten)]
+    pub flow: Flow
+}
+
+impl LeaderboardRow {
+    /// Lists the givers, then the takers.
+    pub fn rows(leaderboard: Leaderboard) -> Vec<LeaderboardRow> {
+        let row = |list: &'static str| move |entry: LeaderboardEntry| LeaderboardRow {
+            list,
+            rank: entry.rank,
+            name: entry.name,
+            uuid: entry.uuid,
+            flow: entry.flow
+        };
+
+        leaderboard.givers.into_iter().map(row("givers"))
+            .chain(leaderboard.takers.into_iter().map(row("takers")))
+            .collect()
+    }
+}
+
+impl Row for LeaderboardRow {
+    const CSV_HEADER: &'static str = "list,rank,name,uuid,ratio,inserted,removed,insertions,removals";
+
+    fn to_csv(&self) -> String {
+        vec![
+            self.list.to_string(),
+            self.rank.to_string(),
+            csv_field(&self.name),
+            self.uuid.to_string(),
+            flow_csv(&self.flow)
+        ].join(",")
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Splits a CSV record into its fields, unescaping quoted ones.
+    fn parse_csv(record: &str) -> Vec<String> {
+        let mut fields = vec![String::new()];
+        let mut chars = record.chars().peekable();
+        let mut quoted = false;
+
+        while let Some(c) = chars.next() {
+            match (c, quoted) {
+                ('"', true) if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    fields.last_mut().unwrap().push('"');
+                },
+                ('"', _) => quoted = !quoted,
+                (',', false) => fields.push(String::new()),
+                (c, _) => fields.last_mut().unwrap().push(c)
+            }
+        }
+
+        fields
+    }
+
+    #[test]
+    fn csv_formulas_are_neutralized() {
+        assert_eq!(csv_field("=cmd|' /C calc'!A0"), "'=cmd|' /C calc'!A0");
+        assert_eq!(csv_field("-1+1"), "'-1+1");
+        assert_eq!(csv_field("+1"), "'+1");
+        assert_eq!(csv_field("@x"), "'@x");
+        assert_eq!(csv_field("\tx"), "'\tx");
+        assert_eq!(csv_field("a=b"), "a=b");
+    }
+
+    #[test]
+    fn csv_fields_are_quoted() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field(""), "");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field("=a,\"b\""), "\"'=a,\"\"b\"\"\"");
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let row = RatioRow {
+            group: Some(String::from("069a79f444e94726a5befca90e38aaf5")),
+            group_name: Some(String::from("=HYPERLINK(\"x\")")),
+            id: String::from("minecraft:stone"),
+            display_name: String::from("Stone, \"smooth\"\nand polished"),
+            flow: Flow { ratio: -3, inserted: 2, removed: 5, insertions: 1, removals: 2 },
+            value: 1.5
+        };
+
+        let fields = parse_csv(&row.to_csv());
+
+        assert_eq!(fields.len(), RatioRow::CSV_HEADER.split(',').count());
+        assert_eq!(fields, vec![
+            "069a79f444e94726a5befca90e38aaf5",
+            "'=HYPERLINK(\"x\")",
+            "minecraft:stone",
+            "Stone, \"smooth\"\nand polished",
+            "-3", "2", "5", "1", "2",
+            "1.5"
+        ]);
+    }
+}
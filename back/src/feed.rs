@@ -0,0 +1,117 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::stream::{self, StreamExt};
+use rocket::request::{self, FromRequest, Outcome, Request};
+use rocket::response::Stream;
+use tokio::io::stream_reader;
+
+use crate::database::query_new_transactions;
+use crate::export::BodyStream;
+use crate::locales::MinecraftLocale;
+use crate::query::Filters;
+use crate::source::{DataError, DataSource, Source};
+
+
+/// How often the data source is polled for new transactions.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The maximal number of records read from the data source at each poll. A larger backlog is
+/// sent over the next polls, without waiting for the poll interval.
+const POLL_BATCH_SIZE: usize = 1000;
+
+/// A stream of [Server-Sent Events](https://html.spec.whatwg.org/multipage/server-sent-events.html).
+pub type EventStream = BodyStream;
+
+/// The state of a transactions feed between two polls.
+struct Feed {
+    source: Source,
+    filters: Arc<Filters>,
+    locale: Arc<MinecraftLocale>,
+
+    /// The ID of the last record read, or `None` if the start of the feed is still unknown.
+    last_id: Option<u64>,
+
+    /// Whether the next poll has to wait for the poll interval. It doesn't if the feed was just
+    /// opened or if there are records left to read.
+    wait: bool
+}
+
+/// The `Last-Event-ID` header sent by `EventSource` when reconnecting to a feed: the ID of the
+/// last transaction received. Missing or invalid values are ignored.
+pub struct LastEventId(pub Option<u64>);
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for LastEventId {
+    type Error = ();
+
+    async fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(LastEventId(
+            request.headers().get_one("Last-Event-ID").and_then(|id| id.trim().parse::<u64>().ok())
+        ))
+    }
+}
+
+/// Follows the container transactions matching the filters as they are recorded, as
+/// Server-Sent Events. Each transaction is sent as a `transaction` event whose data is the
+/// transaction as returned by `/transactions`; a comment is sent when nothing happened, so that
+/// closed connections are detected. Query failures are sent as `error` events, and the feed
+/// then retries at the next poll.
+///
+/// Only transactions recorded after the feed is opened are sent, or after `last_event_id` if
+/// set, so that a reconnecting client misses nothing.
+pub(crate) fn transactions_feed(source: Source, filters: Filters, locale: Arc<MinecraftLocale>, last_event_id: Option<u64>) -> EventStream {
+    let feed = Feed { source, filters: Arc::new(filters), locale, last_id: last_event_id, wait: false };
+
+    let events = stream::unfold(feed, |mut feed| async move {
+        if feed.wait {
+            tokio::time::delay_for(POLL_INTERVAL).await;
+        }
+
+        let filters = Arc::clone(&feed.filters);
+        let locale = Arc::clone(&feed.locale);
+        let last_id = feed.last_id;
+
+        let events = match feed.source.run(move |source: &dyn DataSource| poll(source, &filters, last_id, &locale)).await {
+            Ok((last_id, events, more)) => {
+                feed.last_id = Some(last_id);
+                feed.wait = !more;
+                events
+            },
+            Err(_) => {
+                feed.wait = true;
+                String::from("event: error\ndata: {\"error\":\"Unable to query transactions\"}\n\n")
+            }
+        };
+
+        Some((Ok(Bytes::from(events)), feed))
+    });
+
+    Stream::from(stream_reader(events.boxed()))
+}
+
+/// Reads at most one batch of the transactions recorded after `last_id`, and returns the ID of
+/// the last record read with the corresponding events, and whether there are records left to
+/// read. Without a `last_id`, only the current last ID of the data source is read.
+fn poll(source: &dyn DataSource, filters: &Filters, last_id: Option<u64>, locale: &MinecraftLocale) -> Result<(u64, String, bool), DataError> {
+    let last_id = match last_id {
+        Some(last_id) => last_id,
+        None => return Ok((source.last_id()?, String::from(": connected\n\n"), false))
+    };
+
+    let new_transactions = query_new_transactions(source, filters, last_id, POLL_BATCH_SIZE, locale)?;
+
+    let mut events = String::new();
+
+    for transaction in new_transactions.transactions {
+        let data = serde_json::to_string(&transaction).unwrap_or_default();
+        events.push_str(&format!("event: transaction\nid: {}\ndata: {}\n\n", transaction.id, data));
+    }
+
+    if events.is_empty() {
+        events.push_str(": keep-alive\n\n");
+    }
+
+    Ok((new_transactions.last_id, events, new_transactions.more))
+}
@@ -0,0 +1,111 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tokio::time::timeout;
+
+use crate::locales::MinecraftLocales;
+use crate::servers::Servers;
+use crate::source::DataSource;
+
+
+/// Checks taking longer than this fail, so that a hung database doesn't hang the endpoint. The
+/// query itself goes on until the database timeouts (see `crate::source::Database`) end it.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Ok,
+    Failed
+}
+
+/// The result of a readiness check, with its `latency` in milliseconds.
+#[derive(Serialize, Debug, Clone)]
+pub struct Check {
+    pub name: String,
+    pub status: CheckStatus,
+    pub latency: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>
+}
+
+/// The results of every readiness check. The instance is ready if they all pass.
+#[derive(Serialize, Debug, Clone)]
+pub struct Readiness {
+    pub status: CheckStatus,
+    pub checks: Vec<Check>
+}
+
+impl Readiness {
+    /// Replaces the errors of failed checks with a generic one, for callers who may not see
+    /// their details (e.g. database hosts or users).
+    pub fn redacted(mut self) -> Self {
+        for check in self.checks.iter_mut() {
+            if check.error.is_some() {
+                check.error = Some(String::from("Check failed"));
+            }
+        }
+
+        self
+    }
+}
+
+/// Checks that the database of every server is reachable and has the tables read by its data
+/// source, and that translations load.
+pub(crate) async fn readiness(servers: &Servers, locales: &MinecraftLocales) -> Readiness {
+    let mut checks = vec![];
+
+    for server in servers.iter() {
+        checks.push(check(format!("{}:database", server.id), async {
+            server.source.run(|source: &dyn DataSource| source.ping()).await
+                .map_err(|error| error.to_string())
+        }).await);
+
+        checks.push(check(format!("{}:tables", server.id), async {
+            match server.source.run(|source: &dyn DataSource| source.missing_tables()).await {
+                Ok(missing) if missing.is_empty() => Ok(()),
+                Ok(missing) => Err(format!("Missing tables: {}", missing.join(", "))),
+                Err(error) => Err(error.to_string())
+            }
+        }).await);
+    }
+
+    let locales = locales.clone();
+    checks.push(check(String::from("translations"), async {
+        rocket::tokio::task::spawn_blocking(move || locales.check()).await
+            .map_err(|error| error.to_string())
+            .and_then(|result| result)
+    }).await);
+
+    let status = match checks.iter().all(|check| check.status == CheckStatus::Ok) {
+        true => CheckStatus::Ok,
+        false => CheckStatus::Failed
+    };
+
+    Readiness { status, checks }
+}
+
+/// Runs a check, timing it. Failures are logged.
+async fn check<F: Future<Output = Result<(), String>>>(name: String, check: F) -> Check {
+    let start = Instant::now();
+
+    let result = match timeout(CHECK_TIMEOUT, check).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("Timed out after {} seconds", CHECK_TIMEOUT.as_secs()))
+    };
+
+    if let Err(error) = &result {
+        eprintln!("Readiness check {} failed: {}", name, error);
+    }
+
+    Check {
+        name,
+        status: match result {
+            Ok(()) => CheckStatus::Ok,
+            Err(_) => CheckStatus::Failed
+        },
+        latency: start.elapsed().as_secs_f64() * 1000.0,
+        error: result.err()
+    }
+}
@@ -26,6 +26,31 @@ impl MinecraftLocales {
             default_locale: String::new()
         }
     }
+
+    /// Checks that the default locale is available and that its translations load. Passes if
+    /// translations are disabled.
+    pub fn check(&self) -> Result<(), String> {
+        if self.default_locale.is_empty() {
+            return Ok(());
+        }
+
+        match self.locales.get(&self.default_locale) {
+            Some(locale) => match locale.check()? {
+                0 => Err(format!("No translations found in {:?}", locale.file)),
+                _ => Ok(())
+            },
+            None => Err(format!("The default locale {} is not available", self.default_locale))
+        }
+    }
+
+    /// Returns the given locale, or the default one if it's not available, or an empty dummy
+    /// locale returning the translation keys if the default one was improperly configured.
+    pub fn get(&self, locale: &str) -> Arc<MinecraftLocale> {
+        match self.locales.get(&locale.to_lowercase()).or_else(|| self.locales.get(&self.default_locale)) {
+            Some(locale) => Arc::clone(locale),
+            None => Arc::new(MinecraftLocale::empty())
+        }
+    }
 }
 
 impl From<TranslationsConfigInner> for MinecraftLocales {
@@ -103,6 +128,27 @@ impl MinecraftLocale {
         }
     }
 
+    /// Loads the translations if needed, and returns their number. Unlike [`translate`], a
+    /// failed load is not cached.
+    ///
+    /// [`translate`]: MinecraftLocale::translate
+    pub fn check(&self) -> Result<usize, String> {
+        let mut translations = match self.translations.lock() {
+            Ok(translations) => translations,
+            Err(poisoned) => poisoned.into_inner()
+        };
+
+        if let Some(translations) = (*translations).as_ref() {
+            return Ok(translations.len());
+        }
+
+        let loaded = self.load()?;
+        let count = loaded.len();
+        *translations = Some(loaded);
+
+        Ok(count)
+    }
+
     pub fn translate(&self, key: String) -> String {
         // We either use the data read-only or replace it completely (if empty), so
         // it's safe to accept poisoned mutexes.
@@ -174,20 +220,8 @@ impl<'a, 'r> FromRequest<'a, 'r> for Locale {
             None => locales.default_locale.clone()
         }.to_lowercase();
 
-        // We try to load the requested locale, then the default locale, then if it was improperly
-        // configured, an empty dummy locale structure that returns the translation keys.
-        match locales.locales.get(&requested_locale) {
-            Some(locale) => Outcome::Success(Locale {
-                locale: Arc::clone(locale)
-            }),
-            None => match locales.locales.get(&locales.default_locale) {
-                Some(locale) => Outcome::Success(Locale {
-                    locale: Arc::clone(locale)
-                }),
-                None => Outcome::Success(Locale {
-                    locale: Arc::new(MinecraftLocale::empty())
-                })
-            }
-        }
+        Outcome::Success(Locale {
+            locale: locales.get(&requested_locale)
+        })
     }
 }
@@ -1,45 +1,77 @@
 #![feature(proc_macro_hygiene, decl_macro)]
 
 extern crate cached;
-#[macro_use] extern crate mysql;
 #[macro_use] extern crate rocket;
 #[macro_use] extern crate rocket_contrib;
 extern crate serde;
 extern crate serde_json;
 
+mod alerts;
 mod area;
+mod area_store;
+mod audit;
+mod auth;
+mod cli;
 mod config;
 mod database;
+mod digest;
+mod export;
+mod feed;
+mod health;
 mod params;
+mod players;
+mod query;
 mod locales;
+mod metrics;
+mod servers;
+mod source;
+mod summary;
+mod users;
+mod values;
+mod webhook;
 
-use figment::{Figment, providers::{Env, Format, Serialized, Toml}};
+use figment::{Figment, providers::{Env, Format as _, Serialized, Toml}};
 use itertools::Itertools;
 use rocket::fairing::AdHoc;
-use rocket::http::Header;
-use rocket::response::status::BadRequest;
+use rocket::http::{ContentType, Cookie, CookieJar, Header, SameSite, Status};
+use rocket::http::uri::Origin;
+use rocket::response::content::Content;
+use rocket::response::status::{BadRequest, Custom, NotFound};
 use rocket::State;
 use rocket_contrib::helmet::SpaceHelmet;
 use rocket_contrib::json::{Json, JsonValue};
+use rocket_contrib::uuid::Uuid;
 
 use rocket::logger::PaintExt;
 
-use crate::area::{Area, Areas};
-use crate::config::{AreasConfig, CorsConfig, TranslationsConfig};
-use crate::database::{Player, Ratios, query_recent_players, query_ratios};
-use crate::params::{AreasIds, Uuids};
+use crate::alerts::{Alert, Alerts};
+use crate::area::{Area, AreaNode};
+use crate::area_store::AreaCreation;
+use crate::audit::{AUDIT_CSV_HEADER, AuditEntry, AuditFilters, AuditLog};
+use crate::auth::{Admin, Authenticator, ClientIp, Credentials, LoginError, Moderator, SESSION_COOKIE, SessionInfo, User, Viewer};
+use crate::config::{AlertsConfig, AreasConfig, AuditConfig, AuthConfig, ConfigAreaEntry, CorsConfig, DigestConfig, ItemValuesConfig, MetricsConfig, PlayersConfig, ServersConfig, SourceConfig, SummaryConfig, TranslationsConfig};
+use crate::digest::Digests;
+use crate::export::{Export, Format, LeaderboardRow, RatioRow, TimelineRow};
+use crate::feed::{EventStream, LastEventId, transactions_feed};
+use crate::health::{CheckStatus, Readiness, readiness};
+use crate::database::{caches_stats, Leaderboard, Player, PlayerProfile, Ratios, Timeline, Transactions, query_leaderboard, query_player_profile, query_recent_players, query_ratios, query_summary_ratios, query_timeline, query_transactions};
+use crate::params::{AreasIds, Bucket, GroupBy, TimeBound, Uuids};
 use crate::locales::{MinecraftLocales, Locale};
+use crate::metrics::{AreaFlows, METRICS, MetricsWriter, RequestStart};
+use crate::players::PlayerExclusions;
+use crate::query::Filters;
+use crate::servers::{Server, ServerInfo, Servers};
+use crate::source::DataSource;
+use crate::summary::{Summary, SummaryStatus};
+use crate::values::ItemValues;
 use rocket::yansi::Paint;
 use std::sync::Arc;
+use std::time::Instant;
 
 
 type Result<T> = std::result::Result<T, BadRequest<Json<JsonValue>>>;
 
 
-#[database("prism")]
-struct PrismDatabase(mysql::Conn);
-
-
 #[get("/")]
 fn index() -> &'static str {
     "
@@ -49,53 +81,723 @@ fn index() -> &'static str {
 
             Displays this help.
 
-        GET /players
+    AUTHENTICATION
+
+        Every endpoint but this one, `/health` and `/ready` requires authentication, either
+        with an API token (created with the `users add-token` command) or with a session token
+        (obtained through `/login`), sent as an `Authorization: Bearer <token>` header.
+        Browsers can also use the session cookie set by `/login`. If an `anonymous_role` is
+        configured in the `auth` section, requests without credentials get this role.
+
+        Each endpoint requires a role: viewers can read aggregated data (areas, ratios,
+        timelines, leaderboards, and players list); moderators can also investigate individual
+        players (profiles and transactions); administrators can also manage areas. Requests
+        without valid credentials fail with a 401 status; requests without the required role
+        with a 403 status.
+
+        POST /login
+
+            Opens a session. The JSON body contains the user `name` and `password`. Returns the
+            session `token`, the user `name` and `role`, and the `expires` timestamp.
+
+            After 3 failed logins for a user name or from a client IP, each new attempt must
+            wait for a delay doubling with each failure, up to 5 minutes; until then, logins
+            fail with a 429 status and the `retry_after` delay in seconds. Failures are
+            forgotten after a successful login, or an hour without failures.
+
+        POST /logout
+
+            Closes the current session, if any.
+
+        GET /me
+
+            Returns the authenticated user `name` and `role`.
+
+    SERVERS
+
+        GET /servers (viewer)
+
+            Returns the list of Minecraft servers served by this instance, with their `id`,
+            `name`, and data source `kind` (“prism” or “coreprotect”).
+
+            Data and areas endpoints are scoped to a server: their paths start with
+            `/servers/<server>`, where `<server>` is the server ID. If no servers are
+            configured, the only server has the “default” ID.
+
+        GET /servers/<server>/summary (viewer)
+
+            Returns the state of the summary store of the server, if enabled in the `summary`
+            configuration section: whether it's `ready` (caught up with the data source since it
+            was last built), the `last_id` and `last_epoch` of the last ingested transaction,
+            the `source_last_id` of the data source and the `lag` (number of records not
+            ingested yet), the `last_run` timestamp of the ingester, and its last `error`.
+
+            The store is rebuilt from scratch when areas change; ratios are computed from the
+            data source in the meantime.
+
+    DATA
+
+        The players, ratios, timeline, leaderboard and transactions endpoints (and `/audit`)
+        can also return CSV or NDJSON (one JSON object per line), with a `format` query
+        parameter (“json”, “csv” or “ndjson”) or an `Accept: text/csv` or
+        `Accept: application/x-ndjson` header; an unknown `format` is a 400 error. Results are
+        computed as a whole like for JSON, then sent row by row:
+        - ratios have a row per item (and per player or area with `group_by`), with the
+          `group` and `group_name` columns filled in when broken down;
+        - timelines have a row per bucket and item, with the bucket `start`;
+        - leaderboards have a row per entry, with the `list` (“givers” or “takers”);
+        - transactions have a row per transaction, and the cursor of the next page is sent
+          in the `X-Next-Cursor` header.
+        Totals and time bounds are only part of the JSON response.
 
-            Returns a list of recently active players, according to Prism's records.
+        GET /servers/<server>/players (viewer)
+
+            Returns a list of recently active players, according to the data source's records.
             Add a `filter` query parameter to filter by username.
 
+            Non-player entries (entities, causes…) are excluded according to the rules of the
+            `players` configuration section. Add a `debug=true` query parameter to keep them,
+            with an `excluded_by` key telling which rule excluded each of them.
+
+            Results are cached for one minute.
+
+        GET /servers/<server>/players/<uuid> (moderator)
+
+            Returns the profile of the given player: name, UUID, first and last recorded
+            actions, number of records per action, container activity in each configured
+            area, and worlds they were active in. Responds with a 404 status if the player is
+            unknown to the data source.
+
             Results are cached for one minute.
 
-        GET /ratios?areas=<areas>&players=<players>&locale=<locale>
+        GET /servers/<server>/ratios?areas=<areas>&players=<players>&since=<since>&until=<until>&group_by=<group_by>&exact=<exact>&locale=<locale> (viewer)
 
             Returns the ratio of the given player(s) in the given area(s).
-            - `areas` is a comma-separated list of areas. If missing, all areas are searched.
+            - `areas` is a comma-separated list of areas or groups of areas. If missing, all
+               areas are searched.
             - `players` is a comma-separated list of UUIDs.
+            - `since` and `until` restrict the results to a time window. They can be UNIX
+               timestamps, ISO-8601 dates (e.g. “2020-11-17” or “2020-11-17T18:00:00Z”), or
+               relative durations (e.g. “30d” for thirty days ago; units are s, m, h, d and w).
+               If missing, the whole history is used. The resolved bounds are echoed back
+               in the response as timestamps.
+            - `group_by` can be set to “player” to also get the ratio of each player, keyed by
+               UUID and with their name, in a `players` key (non-player entries are then
+               excluded, like for `/players`); or to “area” to also get the ratio
+               in each area, keyed by area ID and with its name, in an `areas` key. A
+               transaction in overlapping areas counts for the most specific one (sub-areas
+               before their parent), then for the first one by ID.
             - `locale` is the locale to use for the display names (e.g. “ja_jp” or “ru_ru”). If
                missing, the app's default locale will be used.
 
-            Data will be aggregated as a whole from all areas and all players.
+            Alongside the net `ratio`, each item (and the whole) comes with the gross amounts of
+            items `inserted` and `removed`, and the number of `insertions` and `removals`.
+
+            Each item ratio comes with a `value`, which is the ratio weighted by the item value
+            configured in the `item_values` section; the global `value` is their sum.
+
+            Data will be aggregated as a whole from all areas and all players, unless a
+            `group_by` is requested (in which case the aggregated data is still returned).
+
+            If the summary store is enabled and up-to-date (see `/servers/<server>/summary`),
+            ratios are computed from it, at a one-day resolution: `since` is rounded down and
+            `until` up to midnight (UTC), and the rounded bounds are echoed back. The response
+            then has `summarized` set to true. Add `exact=true` to query the data source
+            instead; these results are cached for ten minutes.
+
+        GET /servers/<server>/ratios/timeline?areas=<areas>&players=<players>&since=<since>&until=<until>&bucket=<bucket>&locale=<locale> (viewer)
+
+            Returns the same data as `/ratios`, split into time buckets, so you can see when
+            the ratio of the given player(s) changed.
+            - `bucket` is the size of each bucket: “hour”, “day” (default), “week” or “month”.
+            - Other parameters are the same as for `/ratios`.
+
+            Each bucket contains its start timestamp, the global ratio and the per-item detail.
+            Buckets without any transaction are omitted.
+            Results are cached for ten minutes.
+
+        GET /servers/<server>/transactions?areas=<areas>&players=<players>&material=<material>&since=<since>&until=<until>&cursor=<cursor>&limit=<limit>&locale=<locale> (moderator)
+
+            Returns the individual container transactions behind a ratio, most recent first,
+            with their timestamp, action, item, amount, coordinates, world, area and player.
+            - `areas`, `since`, `until` and `locale` work like for `/ratios`.
+            - `players` is a comma-separated list of UUIDs. If missing, all players are listed.
+            - `material` is a comma-separated list of items (e.g. “diamond,minecraft:emerald”).
+               If missing, all items are listed.
+            - `limit` is the size of a page (default 100, max 1000).
+            - `cursor` is the `next_cursor` value returned by the previous page, if any.
+
+            Results are not cached.
+
+        GET /servers/<server>/stream/transactions?areas=<areas>&players=<players>&material=<material>&locale=<locale> (moderator)
+
+            Follows the container transactions as they are recorded, as Server-Sent Events
+            (`text/event-stream`), e.g. with `EventSource` in a browser. Only transactions
+            recorded after the stream is opened are sent, or after the transaction ID given in
+            the `Last-Event-ID` header, which `EventSource` sends when it reconnects.
+            - `areas`, `players`, `material` and `locale` work like for `/transactions`.
+
+            Each transaction is sent as a `transaction` event, with its ID as the event ID and
+            the transaction (same format as for `/transactions`) as data. The data source is
+            polled every two seconds, 1000 records at a time (a larger backlog is sent over the
+            next polls, without waiting); if it fails, an `error` event is sent and the next
+            poll retries.
+
+        GET /servers/<server>/areas (viewer)
+
+            Returns the tree of available areas and groups of areas. Each node has an `id`, a
+            `name`, the `area` details (unless it's only a group), and its `children` (sub-areas
+            or group members). Groups IDs can be used wherever areas IDs are expected.
+
+        GET /servers/<server>/areas/<id>/leaderboard?since=<since>&until=<until>&limit=<limit> (viewer)
+
+            Ranks every player who used containers in the given area by net contribution.
+            - `givers` lists the players who put the most items in the area;
+            - `takers` lists the players who took the most items from the area.
+            - `limit` is the maximal number of players in each list (default 10, max 1000).
+            - `since` and `until` restrict the results to a time window, like for `/ratios`.
+
+            Non-player entries are excluded, like for `/players`.
+
             Results are cached for ten minutes.
 
-        GET /areas
+    ALERTS
+
+        GET /alerts (moderator)
+
+            Returns the configured alert rules and their state. Rules are evaluated
+            periodically against the ratios (see `/ratios`), and fire for every player, area
+            or total whose ratio crosses their thresholds; new breaches are notified to a
+            Discord-compatible webhook, once until they end.
+            - `server`, `areas`, `material`, `window` (the `since` bound), `per` (“player”,
+              “area” or “total”), `metric` (“ratio” or “value”), `below` and `above` describe
+              the rule.
+            - `state.last_run` is when the rule was last evaluated, and `state.error` the
+              error it failed with, if any.
+            - `state.last_fired` is when the rule last found a new breach.
+            - `state.breaches` lists the ongoing breaches, the most severe first, with their
+              `subject` (player UUID, area ID or “total”), `name`, `value`, the time they
+              were first found (`since`), and whether they were `notified`.
+
+    MONITORING
+
+        GET /health
+
+            Returns `{\"status\": \"ok\"}` and the `version` of Panoptès as long as the process
+            is up. Doesn't require authentication.
+
+        GET /ready
+
+            Checks that Panoptès can serve data, and returns the `status` (“ok” or “failed”)
+            with the `checks`: for every server, whether its database is reachable
+            (“<server>:database”) and has the tables read by its data source
+            (“<server>:tables”); and whether translations load (“translations”, passing if
+            they are disabled). Each check has a `name`, a `status`, its `latency` in
+            milliseconds, and an `error` if it failed; checks time out after five seconds.
+            Responds with a 503 status if a check failed. Doesn't require authentication, but
+            errors are only detailed for moderators (they are always logged).
+
+        GET /metrics (viewer)
+
+            Returns metrics in the Prometheus text format: requests counts and latencies per
+            route, data source queries durations, errors and running queries (each holding a
+            connection of the pool) per server, hits and misses of the query caches, and the
+            lag of the summary stores. With `area_flows` in the `metrics` configuration
+            section, the net flow of every area since the first record is also exposed, in
+            total and for each configured material. It is computed like `/ratios` in the
+            background, every `interval` seconds (5 minutes by default).
+
+    ADMINISTRATION
+
+        These endpoints require the admin role.
+
+        POST /servers/<server>/areas
+
+            Creates a top-level area or group. The JSON body contains its `id` and the `area`
+            itself, using the same format as in the `areas` configuration sections, e.g.
+            `{\"id\": \"shop\", \"area\": {\"name\": \"Shop\", \"world\": \"world\", \"pos1\": [0, 0, 0], \"pos2\": [10, 10, 10]}}`.
+            Fails if an area or group already exists with this ID. IDs may only contain
+            letters, digits, `_` and `-`.
+
+        PUT /servers/<server>/areas/<id>
 
-            Returns a list of available areas."
+            Creates or replaces the given top-level area or group. The JSON body is the area,
+            using the same format as in the `areas` configuration sections.
+
+        DELETE /servers/<server>/areas/<id>
+
+            Deletes the given top-level area or group.
+
+        These three endpoints return the new areas tree, like `GET /areas`. Changes are
+        validated like the configuration, persisted to the `areas_overlay` file of the server
+        (so they survive restarts), and applied immediately; cached results involving areas are
+        cleared.
+
+        GET /audit?user=<user>&endpoint=<endpoint>&search=<search>&since=<since>&until=<until>&limit=<limit>
+
+            Returns the audit log, most recent first. Every call to the players, player, ratios,
+            timeline, leaderboard, transactions and transactions stream endpoints is recorded
+            with its `timestamp`, the requesting `user` and their `role`, the `endpoint`, its
+            query string `parameters`, and its outcome: the number of `results` (null for
+            streams), or the `error` returned if the call failed, including when it was rejected
+            (e.g. an unknown server or area, or an invalid `format`).
+            - `user` restricts the log to the calls of this user.
+            - `endpoint` restricts the log to the endpoints starting with this path (e.g.
+               “/servers/survival/players”).
+            - `search` restricts the log to the calls whose parameters contain this text (e.g.
+               a player UUID).
+            - `since` and `until` restrict the log to a time window, like for `/ratios`.
+            - `limit` is the maximal number of entries (default 100).
+
+        GET /audit/export?user=<user>&endpoint=<endpoint>&search=<search>&since=<since>&until=<until>&limit=<limit>
+
+            Exports the audit log as CSV, with the same filters as `/audit`. All matching
+            entries are exported unless a `limit` is set."
 }
 
 
-#[get("/areas")]
-fn areas(areas: State<Areas>) -> Json<Vec<Area>>{
-    Json(areas.inner().clone().into())
+#[post("/login", format = "json", data = "<credentials>")]
+fn login(credentials: Json<Credentials>, client: ClientIp, authenticator: State<Authenticator>, cookies: &CookieJar<'_>) -> std::result::Result<Json<SessionInfo>, Custom<Json<JsonValue>>> {
+    let (name, role) = match authenticator.login(&credentials, client.0) {
+        Ok(user) => user,
+        Err(LoginError::InvalidCredentials) => return Err(Custom(Status::BadRequest, Json(json!({ "error": "Invalid name or password." })))),
+        Err(LoginError::Throttled(delay)) => return Err(Custom(
+            Status::TooManyRequests,
+            Json(json!({ "error": format!("Too many failed logins, retry in {} seconds.", delay), "retry_after": delay }))
+        ))
+    };
+
+    let (token, expires) = authenticator.open_session(name.clone());
+
+    cookies.add(
+        Cookie::build(SESSION_COOKIE, token.clone())
+            .path("/")
+            .http_only(true)
+            .secure(authenticator.secure_cookie)
+            .same_site(SameSite::Strict)
+            .finish()
+    );
+
+    Ok(Json(SessionInfo { token, name, role, expires }))
 }
 
 
-#[get("/players?<filter>")]
-async fn players(filter: Option<String>, db: PrismDatabase) -> Result<Json<Vec<Player>>> {
-    match db.run(|c: &mut mysql::Conn| query_recent_players(c, filter.unwrap_or(String::from("")))).await {
-        Ok(players) => Ok(Json(players)),
-        Err(_) => Err(BadRequest(Some(Json(json!({ "error": "Unable to query players" })))))
+#[post("/logout")]
+fn logout(user: User, authenticator: State<Authenticator>, cookies: &CookieJar<'_>) -> Json<JsonValue> {
+    if let Some(session) = user.session {
+        authenticator.close_session(&session);
     }
+
+    cookies.remove(Cookie::named(SESSION_COOKIE));
+
+    Json(json!({ "status": "ok" }))
+}
+
+
+#[get("/me")]
+fn me(user: User) -> Json<User> {
+    Json(user)
+}
+
+
+/// Answers CORS preflight requests; headers are added by the CORS fairing.
+#[options("/<_path..>")]
+fn preflight(_path: std::path::PathBuf) -> &'static str {
+    ""
+}
+
+
+#[get("/health")]
+fn health() -> Json<JsonValue> {
+    Json(json!({ "status": "ok", "version": env!("CARGO_PKG_VERSION") }))
+}
+
+
+#[get("/ready")]
+async fn ready(moderator: Option<Moderator>, servers: State<'_, Servers>, locales: State<'_, MinecraftLocales>) -> Custom<Json<Readiness>> {
+    let readiness = readiness(servers.inner(), locales.inner()).await;
+    let readiness = match moderator {
+        Some(_) => readiness,
+        None => readiness.redacted()
+    };
+
+    let status = match readiness.status {
+        CheckStatus::Ok => Status::Ok,
+        CheckStatus::Failed => Status::ServiceUnavailable
+    };
+
+    Custom(status, Json(readiness))
 }
 
 
-#[get("/ratios?<areas>&<players>")]
-async fn ratios(areas: AreasIds, players: Uuids, areas_state: State<'_, Areas>, locale: Locale, db: PrismDatabase) -> Result<Json<Ratios>> {
-    let areas: Vec<Area> = areas_state.filter(areas).areas.iter().map(|(_, a)| a.clone()).collect();
-    match areas.len() {
-        0 => Err(BadRequest(Some(Json(json!({ "error": "There are no areas matching your request." }))))),
-        _ => match db.run(move |c: &mut mysql::Conn| query_ratios(c, areas, players, Arc::clone(&*locale))).await {
-            Ok(ratios) => Ok(Json(ratios)),
-            Err(_) => Err(BadRequest(Some(Json(json!({ "error": "Unable to query ratios" })))))
+#[get("/metrics")]
+fn metrics(_viewer: Viewer, servers: State<Servers>, config: State<MetricsConfig>, area_flows: State<Arc<AreaFlows>>) -> Content<String> {
+    let mut writer = MetricsWriter::default();
+    METRICS.write(&mut writer);
+
+    let caches = caches_stats();
+
+    writer.family("panoptes_cache_hits_total", "counter", "Query results served from the cache, per cache.");
+    for cache in caches.iter() {
+        writer.sample("panoptes_cache_hits_total", &[("cache", cache.name)], cache.hits as f64);
+    }
+
+    writer.family("panoptes_cache_misses_total", "counter", "Query results not found in the cache, per cache.");
+    for cache in caches.iter() {
+        writer.sample("panoptes_cache_misses_total", &[("cache", cache.name)], cache.misses as f64);
+    }
+
+    writer.family("panoptes_cache_entries", "gauge", "Query results in the cache, per cache.");
+    for cache in caches.iter() {
+        writer.sample("panoptes_cache_entries", &[("cache", cache.name)], cache.size as f64);
+    }
+
+    writer.family("panoptes_summary_lag", "gauge", "Records of the data source not ingested yet by the summary store, per server.");
+    for server in servers.iter() {
+        if let Some(lag) = server.summary.as_ref().and_then(|summary| summary.status().lag) {
+            writer.sample("panoptes_summary_lag", &[("server", &server.id)], lag as f64);
+        }
+    }
+
+    if config.metrics.area_flows {
+        area_flows.write(&mut writer);
+    }
+
+    Content(ContentType::Plain, writer.finish())
+}
+
+
+#[get("/alerts")]
+fn alerts(_moderator: Moderator, alerts: State<Arc<Alerts>>) -> Json<Vec<Alert>> {
+    Json(alerts.list())
+}
+
+
+#[get("/servers")]
+fn servers(_viewer: Viewer, servers: State<Servers>) -> Json<Vec<ServerInfo>> {
+    Json(servers.list())
+}
+
+
+#[get("/servers/<server>/summary")]
+fn summary(server: String, _viewer: Viewer, servers: State<Servers>) -> Result<Json<SummaryStatus>> {
+    let server = find_server(servers.inner(), &server)?;
+
+    match &server.summary {
+        Some(summary) => {
+            // Until the ingester rebuilt the store after an areas change, it's not ready anymore.
+            let mut status = summary.status();
+            status.ready = summary.is_ready(&server.areas.get());
+            Ok(Json(status))
+        },
+        None => Err(BadRequest(Some(Json(json!({ "error": "The summary store is not enabled." })))))
+    }
+}
+
+
+/// Returns the server with the given ID, or an error if there is none.
+fn find_server<'a>(servers: &'a Servers, id: &str) -> Result<&'a Server> {
+    servers.get(id).ok_or_else(|| BadRequest(Some(Json(json!({ "error": "This server is unknown." })))))
+}
+
+/// Turns an unknown `?format=` into an error, as the [`Format`] guard alone would fail with an
+/// HTML page.
+fn export_format(format: std::result::Result<Format, String>) -> Result<Format> {
+    format.map_err(|error| BadRequest(Some(Json(json!({ "error": error })))))
+}
+
+/// Records an audited call with its outcome, then returns its response. `result` holds the
+/// number of results along with the response if the call succeeded.
+fn audited<T>(audit: &AuditLog, user: &User, uri: &Origin<'_>, result: Result<(Option<usize>, T)>) -> Result<T> {
+    let outcome = match &result {
+        Ok((results, _)) => Ok(*results),
+        Err(BadRequest(Some(Json(error)))) => Err(error.get("error").and_then(|error| error.as_str()).unwrap_or_default().to_string()),
+        Err(BadRequest(None)) => Err(String::new())
+    };
+
+    audit.record(user, uri, outcome);
+    result.map(|(_, response)| response)
+}
+
+
+#[get("/servers/<server>/areas")]
+fn areas(server: String, _viewer: Viewer, servers: State<Servers>) -> Result<Json<Vec<AreaNode>>> {
+    let server = find_server(servers.inner(), &server)?;
+    Ok(Json(server.areas.get().tree()))
+}
+
+
+#[post("/servers/<server>/areas", format = "json", data = "<creation>")]
+fn create_area(server: String, creation: Json<AreaCreation>, _admin: Admin, servers: State<Servers>) -> Result<Json<Vec<AreaNode>>> {
+    let server = find_server(servers.inner(), &server)?;
+    let creation = creation.into_inner();
+
+    match server.areas.create(creation.id, creation.area) {
+        Ok(areas) => Ok(Json(areas.tree())),
+        Err(errors) => Err(BadRequest(Some(Json(json!({ "error": "Unable to create area", "errors": errors })))))
+    }
+}
+
+
+#[put("/servers/<server>/areas/<id>", format = "json", data = "<area>")]
+fn update_area(server: String, id: String, area: Json<ConfigAreaEntry>, _admin: Admin, servers: State<Servers>) -> Result<Json<Vec<AreaNode>>> {
+    let server = find_server(servers.inner(), &server)?;
+
+    match server.areas.put(id, area.into_inner()) {
+        Ok(areas) => Ok(Json(areas.tree())),
+        Err(errors) => Err(BadRequest(Some(Json(json!({ "error": "Invalid area", "errors": errors })))))
+    }
+}
+
+
+#[delete("/servers/<server>/areas/<id>")]
+fn delete_area(server: String, id: String, _admin: Admin, servers: State<Servers>) -> Result<Json<Vec<AreaNode>>> {
+    let server = find_server(servers.inner(), &server)?;
+
+    match server.areas.delete(id) {
+        Ok(areas) => Ok(Json(areas.tree())),
+        Err(errors) => Err(BadRequest(Some(Json(json!({ "error": "Unable to delete area", "errors": errors })))))
+    }
+}
+
+
+#[get("/servers/<server>/areas/<id>/leaderboard?<since>&<until>&<limit>")]
+async fn leaderboard(server: String, id: String, since: TimeBound, until: TimeBound, limit: Option<usize>, format: std::result::Result<Format, String>, viewer: Viewer, servers: State<'_, Servers>, exclusions: State<'_, PlayerExclusions>, audit: State<'_, AuditLog>, uri: &Origin<'_>) -> Result<Export<Leaderboard>> {
+    let result = async {
+        let format = export_format(format)?;
+        let server = find_server(servers.inner(), &server)?;
+        let area = match server.areas.get().areas.get(&id) {
+            Some(area) => area.clone(),
+            None => return Err(BadRequest(Some(Json(json!({ "error": "There are no areas matching your request." })))))
+        };
+
+        let exclusions = exclusions.inner().clone();
+        let server_id = server.id.clone();
+        let limit = limit.unwrap_or(10).min(1000).max(1);
+
+        match server.source.run(move |source: &dyn DataSource| query_leaderboard(source, &server_id, area, since, until, limit, exclusions)).await {
+            Ok(leaderboard) => Ok((
+                Some(leaderboard.givers.len() + leaderboard.takers.len()),
+                Export::of(format, leaderboard, LeaderboardRow::rows)
+            )),
+            Err(_) => Err(BadRequest(Some(Json(json!({ "error": "Unable to query leaderboard" })))))
+        }
+    }.await;
+
+    audited(&audit, &viewer.0, uri, result)
+}
+
+
+#[get("/servers/<server>/transactions?<areas>&<players>&<material>&<since>&<until>&<cursor>&<limit>")]
+async fn transactions(server: String, areas: AreasIds, players: Option<Uuids>, material: Option<String>, since: TimeBound, until: TimeBound, cursor: Option<u64>, limit: Option<usize>, format: std::result::Result<Format, String>, moderator: Moderator, servers: State<'_, Servers>, locale: Locale, audit: State<'_, AuditLog>, uri: &Origin<'_>) -> Result<Export<Transactions>> {
+    let result = async {
+        let format = export_format(format)?;
+        let server = find_server(servers.inner(), &server)?;
+        let areas: Vec<Area> = server.areas.get().filter(areas).areas.iter().map(|(_, a)| a.clone()).collect();
+        if areas.is_empty() {
+            return Err(BadRequest(Some(Json(json!({ "error": "There are no areas matching your request." })))))
+        }
+
+        let mut filters = Filters::new(areas, players.unwrap_or(Uuids::any()), since, until);
+        filters.materials = material
+            .map(|materials| materials.split(',').map(|m| m.trim().to_lowercase()).filter(|m| !m.is_empty()).collect())
+            .unwrap_or_default();
+
+        let limit = limit.unwrap_or(100).min(1000).max(1);
+        let locale = Arc::clone(&*locale);
+
+        match server.source.run(move |source: &dyn DataSource| query_transactions(source, filters, cursor, limit, locale)).await {
+            Ok(transactions) => {
+                // Exported rows can't hold the cursor of the next page.
+                let count = transactions.transactions.len();
+                let next_cursor = transactions.next_cursor;
+                let export = Export::of(format, transactions, |transactions| transactions.transactions);
+
+                Ok((Some(count), match next_cursor {
+                    Some(cursor) => export.with_header(Header::new("X-Next-Cursor", cursor.to_string())),
+                    None => export
+                }))
+            },
+            Err(_) => Err(BadRequest(Some(Json(json!({ "error": "Unable to query transactions" })))))
+        }
+    }.await;
+
+    audited(&audit, &moderator.0, uri, result)
+}
+
+
+#[get("/servers/<server>/stream/transactions?<areas>&<players>&<material>")]
+async fn stream_transactions(server: String, areas: AreasIds, players: Option<Uuids>, material: Option<String>, last_event_id: LastEventId, moderator: Moderator, servers: State<'_, Servers>, locale: Locale, audit: State<'_, AuditLog>, uri: &Origin<'_>) -> Result<Content<EventStream>> {
+    let result = async {
+        let server = find_server(servers.inner(), &server)?;
+        let areas: Vec<Area> = server.areas.get().filter(areas).areas.iter().map(|(_, a)| a.clone()).collect();
+        if areas.is_empty() {
+            return Err(BadRequest(Some(Json(json!({ "error": "There are no areas matching your request." })))))
+        }
+
+        let mut filters = Filters::new(areas, players.unwrap_or(Uuids::any()), TimeBound::unbounded(), TimeBound::unbounded());
+        filters.materials = material
+            .map(|materials| materials.split(',').map(|m| m.trim().to_lowercase()).filter(|m| !m.is_empty()).collect())
+            .unwrap_or_default();
+
+        // The number of transactions sent is unknown when the stream is opened.
+        Ok((None, Content(ContentType::new("text", "event-stream"), transactions_feed(server.source.clone(), filters, Arc::clone(&*locale), last_event_id.0))))
+    }.await;
+
+    audited(&audit, &moderator.0, uri, result)
+}
+
+
+#[get("/servers/<server>/players?<filter>&<debug>")]
+async fn players(server: String, filter: Option<String>, debug: Option<bool>, format: std::result::Result<Format, String>, viewer: Viewer, servers: State<'_, Servers>, exclusions: State<'_, PlayerExclusions>, audit: State<'_, AuditLog>, uri: &Origin<'_>) -> Result<Export<Vec<Player>>> {
+    let result = async {
+        let format = export_format(format)?;
+        let server = find_server(servers.inner(), &server)?;
+        let exclusions = exclusions.inner().clone();
+        let server_id = server.id.clone();
+
+        match server.source.run(move |source: &dyn DataSource| query_recent_players(source, &server_id, filter.unwrap_or(String::from("")), exclusions, debug.unwrap_or(false))).await {
+            Ok(players) => Ok((Some(players.len()), Export::of(format, players, |players| players))),
+            Err(_) => Err(BadRequest(Some(Json(json!({ "error": "Unable to query players" })))))
+        }
+    }.await;
+
+    audited(&audit, &viewer.0, uri, result)
+}
+
+
+#[get("/servers/<server>/players/<uuid>")]
+async fn player(server: String, uuid: Uuid, moderator: Moderator, servers: State<'_, Servers>, audit: State<'_, AuditLog>, uri: &Origin<'_>) -> Result<std::result::Result<Json<PlayerProfile>, NotFound<Json<JsonValue>>>> {
+    let result = async {
+        let server = find_server(servers.inner(), &server)?;
+        let areas: Vec<Area> = (*server.areas.get()).clone().into();
+        let server_id = server.id.clone();
+
+        match server.source.run(move |source: &dyn DataSource| query_player_profile(source, &server_id, uuid, areas)).await {
+            Ok(Some(profile)) => Ok((Some(1), Ok(Json(profile)))),
+            Ok(None) => Ok((Some(0), Err(NotFound(Json(json!({ "error": "This player is unknown." })))))),
+            Err(_) => Err(BadRequest(Some(Json(json!({ "error": "Unable to query player" })))))
+        }
+    }.await;
+
+    audited(&audit, &moderator.0, uri, result)
+}
+
+
+#[get("/servers/<server>/ratios?<areas>&<players>&<since>&<until>&<group_by>&<exact>")]
+async fn ratios(server: String, areas: AreasIds, players: Uuids, since: TimeBound, until: TimeBound, group_by: GroupBy, exact: Option<bool>, format: std::result::Result<Format, String>, viewer: Viewer, servers: State<'_, Servers>, values: State<'_, ItemValues>, exclusions: State<'_, PlayerExclusions>, locale: Locale, audit: State<'_, AuditLog>, uri: &Origin<'_>) -> Result<Export<Ratios>> {
+    let result = async {
+        let format = export_format(format)?;
+        let server = find_server(servers.inner(), &server)?;
+        let values = values.inner().clone();
+        let areas: Vec<Area> = server.areas.get().filter(areas).areas.iter().map(|(_, a)| a.clone()).collect();
+        match areas.len() {
+            0 => Err(BadRequest(Some(Json(json!({ "error": "There are no areas matching your request." }))))),
+            _ => {
+                let mut filters = Filters::new(areas, players, since, until);
+                if group_by == GroupBy::Player {
+                    filters.exclusions = Some(exclusions.inner().clone());
+                }
+                let locale = Arc::clone(&*locale);
+
+                let ratios = match server.summary.as_ref().filter(|summary| summary.is_ready(&server.areas.get()) && !exact.unwrap_or(false)) {
+                    Some(summary) => summary.run(move |summary: &Summary| query_summary_ratios(summary, filters, group_by, locale, values)).await,
+                    None => {
+                        let server_id = server.id.clone();
+                        server.source.run(move |source: &dyn DataSource| query_ratios(source, &server_id, filters, group_by, locale, values)).await
+                    }
+                };
+
+                match ratios {
+                    Ok(ratios) => Ok((Some(ratios.detail.len()), Export::of(format, ratios, RatioRow::rows))),
+                    Err(_) => Err(BadRequest(Some(Json(json!({ "error": "Unable to query ratios" })))))
+                }
+            }
+        }
+    }.await;
+
+    audited(&audit, &viewer.0, uri, result)
+}
+
+
+#[get("/servers/<server>/ratios/timeline?<areas>&<players>&<since>&<until>&<bucket>")]
+async fn timeline(server: String, areas: AreasIds, players: Uuids, since: TimeBound, until: TimeBound, bucket: Bucket, format: std::result::Result<Format, String>, viewer: Viewer, servers: State<'_, Servers>, values: State<'_, ItemValues>, locale: Locale, audit: State<'_, AuditLog>, uri: &Origin<'_>) -> Result<Export<Timeline>> {
+    let result = async {
+        let format = export_format(format)?;
+        let server = find_server(servers.inner(), &server)?;
+        let values = values.inner().clone();
+        let areas: Vec<Area> = server.areas.get().filter(areas).areas.iter().map(|(_, a)| a.clone()).collect();
+        match areas.len() {
+            0 => Err(BadRequest(Some(Json(json!({ "error": "There are no areas matching your request." }))))),
+            _ => {
+                let server_id = server.id.clone();
+                let locale = Arc::clone(&*locale);
+
+                match server.source.run(move |source: &dyn DataSource| query_timeline(source, &server_id, Filters::new(areas, players, since, until), bucket, locale, values)).await {
+                    Ok(timeline) => Ok((Some(timeline.buckets.len()), Export::of(format, timeline, TimelineRow::rows))),
+                    Err(_) => Err(BadRequest(Some(Json(json!({ "error": "Unable to query timeline" })))))
+                }
+            }
+        }
+    }.await;
+
+    audited(&audit, &viewer.0, uri, result)
+}
+
+
+#[get("/audit?<user>&<endpoint>&<search>&<since>&<until>&<limit>")]
+fn audit(user: Option<String>, endpoint: Option<String>, search: Option<String>, since: TimeBound, until: TimeBound, limit: Option<usize>, format: std::result::Result<Format, String>, _admin: Admin, audit: State<AuditLog>) -> Result<Export<Vec<AuditEntry>>> {
+    let format = export_format(format)?;
+    let filters = AuditFilters { user, endpoint, search, since, until };
+
+    match audit.entries(&filters, Some(limit.unwrap_or(100))) {
+        Ok(entries) => Ok(Export::of(format, entries, |entries| entries)),
+        Err(_) => Err(BadRequest(Some(Json(json!({ "error": "Unable to read audit log" })))))
+    }
+}
+
+
+#[get("/audit/export?<user>&<endpoint>&<search>&<since>&<until>&<limit>")]
+fn audit_export(user: Option<String>, endpoint: Option<String>, search: Option<String>, since: TimeBound, until: TimeBound, limit: Option<usize>, _admin: Admin, audit: State<AuditLog>) -> Result<Content<String>> {
+    let filters = AuditFilters { user, endpoint, search, since, until };
+
+    match audit.entries(&filters, limit) {
+        Ok(entries) => Ok(Content(
+            ContentType::CSV,
+            std::iter::once(String::from(AUDIT_CSV_HEADER))
+                .chain(entries.iter().map(|entry| entry.to_csv()))
+                .map(|line| line + "\n")
+                .collect()
+        )),
+        Err(_) => Err(BadRequest(Some(Json(json!({ "error": "Unable to read audit log" })))))
+    }
+}
+
+
+/// Prints the errors of a configuration section, in the style of Rocket's launch messages.
+fn print_config_errors(section: &str, errors: &[String]) {
+    eprintln!("{}{}{}", Paint::red(Paint::emoji("❌ ")), Paint::magenta(section), Paint::red(" configuration is invalid:"));
+
+    for error in errors {
+        eprintln!("    {} {}", Paint::default("=>").bold(), Paint::red(error));
+    }
+}
+
+/// Returns the states background tasks (alerts and digests) work with: the servers,
+/// translations, values and players exclusions managed by the previous fairings. Fails with
+/// the names of the missing states, if any.
+fn background_states(rocket: &rocket::Rocket) -> std::result::Result<(Servers, MinecraftLocales, ItemValues, PlayerExclusions), String> {
+    match (rocket.state::<Servers>(), rocket.state::<MinecraftLocales>(), rocket.state::<ItemValues>(), rocket.state::<PlayerExclusions>()) {
+        (Some(servers), Some(locales), Some(values), Some(exclusions)) => Ok((servers.clone(), locales.clone(), values.clone(), exclusions.clone())),
+        (servers, locales, values, exclusions) => {
+            let missing = vec![
+                Some("servers").filter(|_| servers.is_none()),
+                Some("translations").filter(|_| locales.is_none()),
+                Some("item values").filter(|_| values.is_none()),
+                Some("players exclusions").filter(|_| exclusions.is_none())
+            ];
+
+            Err(format!("missing state: {} (its fairing must be attached first)", missing.into_iter().flatten().join(", ")))
         }
     }
 }
@@ -109,11 +811,59 @@ fn rocket() -> rocket::Rocket {
         .merge(Toml::file(Env::var_or("PANOPTES_CONFIG", "../Panoptes.toml")).nested())
         .merge(Env::prefixed("PANOPTES_").global());
 
+    // Administration commands (e.g. `users add`) run instead of the server.
+    if let Some(code) = cli::run(&figment) {
+        std::process::exit(code);
+    }
+
     rocket::custom(figment)
-        .mount("/", routes![index, areas, players, ratios])
-        .attach(AdHoc::on_attach("Areas Configuration", |rocket| async {
+        .mount("/", routes![index, health, ready, login, logout, me, preflight, metrics, audit, audit_export, alerts, servers, summary, areas, create_area, update_area, delete_area, leaderboard, players, player, ratios, timeline, transactions, stream_transactions])
+        .attach(AdHoc::on_attach("Servers Configuration", |rocket| async {
+            let figment: &Figment = rocket.figment();
+            let configs = figment.extract::<ServersConfig>().and_then(|servers| {
+                Ok((servers, figment.extract::<AreasConfig>()?, figment.extract::<SourceConfig>()?, figment.extract::<SummaryConfig>()?))
+            });
+
+            let (servers, areas, source, summary) = match configs {
+                Ok(configs) => configs,
+                Err(e) => {
+                    rocket::config::pretty_print_error(e);
+                    return Err(rocket);
+                }
+            };
+
+            let servers: Servers = match Servers::load(servers, areas, source, summary.summary) {
+                Ok(servers) => servers,
+                Err(errors) => {
+                    print_config_errors("Servers", &errors);
+                    return Err(rocket);
+                }
+            };
+
+            Ok(rocket.manage(servers))
+        }))
+        .attach(AdHoc::on_attach("Authentication Configuration", |rocket| async {
+            let figment: &Figment = rocket.figment();
+            let config: AuthConfig = match figment.extract() {
+                Ok(config) => config,
+                Err(e) => {
+                    rocket::config::pretty_print_error(e);
+                    return Err(rocket);
+                }
+            };
+
+            let authenticator: Authenticator = config.into();
+
+            if let Err(error) = authenticator.users.users() {
+                print_config_errors("Authentication", &[error]);
+                return Err(rocket);
+            }
+
+            Ok(rocket.manage(authenticator))
+        }))
+        .attach(AdHoc::on_attach("Audit Configuration", |rocket| async {
             let figment: &Figment = rocket.figment();
-            let config: AreasConfig = match figment.extract() {
+            let config: AuditConfig = match figment.extract() {
                 Ok(config) => config,
                 Err(e) => {
                     rocket::config::pretty_print_error(e);
@@ -121,9 +871,9 @@ fn rocket() -> rocket::Rocket {
                 }
             };
 
-            let areas: Areas = config.into();
+            let audit: AuditLog = config.into();
 
-            Ok(rocket.manage(areas))
+            Ok(rocket.manage(audit))
         }))
         .attach(AdHoc::on_attach("Translations Configuration", |rocket| async {
             let figment: &Figment = rocket.figment();
@@ -147,11 +897,150 @@ fn rocket() -> rocket::Rocket {
 
             Ok(rocket.manage(MinecraftLocales::empty()))
         }))
+        .attach(AdHoc::on_attach("Item Values Configuration", |rocket| async {
+            let figment: &Figment = rocket.figment();
+            let config: ItemValuesConfig = match figment.extract() {
+                Ok(config) => config,
+                Err(e) => {
+                    rocket::config::pretty_print_error(e);
+                    return Err(rocket);
+                }
+            };
+
+            let values: ItemValues = config.into();
+
+            Ok(rocket.manage(values))
+        }))
+        .attach(AdHoc::on_attach("Players Configuration", |rocket| async {
+            let figment: &Figment = rocket.figment();
+            let config: PlayersConfig = match figment.extract() {
+                Ok(config) => config,
+                Err(e) => {
+                    rocket::config::pretty_print_error(e);
+                    return Err(rocket);
+                }
+            };
+
+            let exclusions: PlayerExclusions = config.into();
+
+            Ok(rocket.manage(exclusions))
+        }))
+        .attach(AdHoc::on_attach("Alerts Configuration", |rocket| async {
+            let figment: &Figment = rocket.figment();
+            let config: AlertsConfig = match figment.extract() {
+                Ok(config) => config,
+                Err(e) => {
+                    rocket::config::pretty_print_error(e);
+                    return Err(rocket);
+                }
+            };
+
+            let (servers, locales, values, exclusions) = match background_states(&rocket) {
+                Ok(states) => states,
+                Err(error) => {
+                    print_config_errors("Alerts", &[error]);
+                    return Err(rocket);
+                }
+            };
+
+            let interval = config.alerts.interval;
+            let alerts = match Alerts::load(config.alerts, &servers) {
+                Ok(alerts) => Arc::new(alerts),
+                Err(errors) => {
+                    print_config_errors("Alerts", &errors);
+                    return Err(rocket);
+                }
+            };
+
+            if !alerts.is_empty() {
+                Arc::clone(&alerts).start(servers, locales.get(&locales.default_locale), values, exclusions, interval);
+            }
+
+            Ok(rocket.manage(alerts))
+        }))
+        .attach(AdHoc::on_attach("Digest Configuration", |rocket| async {
+            let figment: &Figment = rocket.figment();
+            let config: DigestConfig = match figment.extract() {
+                Ok(config) => config,
+                Err(e) => {
+                    rocket::config::pretty_print_error(e);
+                    return Err(rocket);
+                }
+            };
+
+            if !config.digest.enabled {
+                return Ok(rocket);
+            }
+
+            let (servers, locales, values, exclusions) = match background_states(&rocket) {
+                Ok(states) => states,
+                Err(error) => {
+                    print_config_errors("Digest", &[error]);
+                    return Err(rocket);
+                }
+            };
+
+            let locale = locales.get(config.digest.locale.as_deref().unwrap_or(&locales.default_locale));
+
+            match Digests::load(config.digest, servers, locale, values, exclusions) {
+                Ok(digests) => digests.start(),
+                Err(errors) => {
+                    print_config_errors("Digest", &errors);
+                    return Err(rocket);
+                }
+            }
+
+            Ok(rocket)
+        }))
         .attach(AdHoc::config::<CorsConfig>())
-        .attach(PrismDatabase::fairing())
+        .attach(AdHoc::on_attach("Metrics Configuration", |rocket| async {
+            let figment: &Figment = rocket.figment();
+            let config: MetricsConfig = match figment.extract() {
+                Ok(config) => config,
+                Err(e) => {
+                    rocket::config::pretty_print_error(e);
+                    return Err(rocket);
+                }
+            };
+
+            let area_flows = Arc::new(AreaFlows::default());
+
+            if config.metrics.area_flows {
+                let (servers, locales, values, _) = match background_states(&rocket) {
+                    Ok(states) => states,
+                    Err(error) => {
+                        print_config_errors("Metrics", &[error]);
+                        return Err(rocket);
+                    }
+                };
+
+                Arc::clone(&area_flows).start(servers, locales.get(&locales.default_locale), values, config.metrics.clone());
+            }
+
+            Ok(rocket.manage(config).manage(area_flows))
+        }))
+        .attach(AdHoc::on_request("Metrics", |req, _| Box::pin(async move {
+            req.local_cache(|| RequestStart(Instant::now()));
+        })))
+        .attach(AdHoc::on_response("Metrics", |req, res| Box::pin(async move {
+            let start = req.local_cache(|| RequestStart(Instant::now()));
+            let route = req.route()
+                .map(|route| route.uri.path().to_string())
+                .unwrap_or_else(|| String::from("unmatched"));
+
+            METRICS.record_request(req.method().as_str(), &route, res.status().code, start.0.elapsed());
+        })))
         .attach(SpaceHelmet::default())
         .attach(AdHoc::on_response("CORS", |req, res| Box::pin(async move {
             let cors_config = req.guard::<rocket::State<'_, CorsConfig>>().await.expect("CorsConfig state not attached");
             res.set_header(Header::new("Access-Control-Allow-Origin", cors_config.cors.clone()));
+            res.set_header(Header::new("Access-Control-Allow-Methods", "GET, POST, PUT, DELETE, OPTIONS"));
+            res.set_header(Header::new("Access-Control-Allow-Headers", "Authorization, Content-Type"));
+            res.set_header(Header::new("Access-Control-Expose-Headers", "X-Next-Cursor"));
+
+            // Browsers only send the session cookie cross-origin to an explicitly allowed origin.
+            if cors_config.cors != "*" {
+                res.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
+            }
         })))
 }
@@ -0,0 +1,298 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+
+use crate::area::Area;
+use crate::config::MetricsConfigInner;
+use crate::database::{query_ratios, query_summary_ratios};
+use crate::locales::MinecraftLocale;
+use crate::params::{GroupBy, TimeBound, Uuids};
+use crate::query::Filters;
+use crate::servers::{Server, Servers};
+use crate::source::DataError;
+use crate::values::ItemValues;
+
+
+/// The metrics of this instance, exposed by the `/metrics` endpoint.
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+/// The time a request was received at, stored in its local cache.
+pub struct RequestStart(pub Instant);
+
+/// The upper bounds of the latency histograms buckets, in seconds.
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// A latency histogram, in the Prometheus sense: `counts[i]` is the number of observations
+/// lower than or equal to `BUCKETS[i]`.
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    counts: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+
+        for (count, bound) in self.counts.iter_mut().zip(BUCKETS.iter()) {
+            if seconds <= *bound {
+                *count += 1;
+            }
+        }
+
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+/// Counters and histograms updated as requests are served and queries are run. Gauges read at
+/// scrape time (e.g. caches) are written by the endpoint with a [`MetricsWriter`], and area flows
+/// by [`AreaFlows`].
+#[derive(Default)]
+pub struct Metrics {
+    /// Requests per method, route and status.
+    requests: Mutex<BTreeMap<(String, String, u16), u64>>,
+    request_durations: Mutex<BTreeMap<(String, String), Histogram>>,
+
+    /// Data source queries per server, and the number of queries running.
+    query_durations: Mutex<BTreeMap<String, Histogram>>,
+    query_errors: Mutex<BTreeMap<String, u64>>,
+    queries_in_flight: Mutex<BTreeMap<String, i64>>
+}
+
+impl Metrics {
+    /// Records a served request. The route is the path pattern of the matched route (e.g.
+    /// `/servers/<server>/ratios`), so that label values stay bounded.
+    pub fn record_request(&self, method: &str, route: &str, status: u16, duration: Duration) {
+        *lock(&self.requests).entry((method.to_string(), route.to_string(), status)).or_default() += 1;
+        lock(&self.request_durations).entry((method.to_string(), route.to_string())).or_default().observe(duration);
+    }
+
+    /// Records the start of a data source query.
+    pub fn start_query(&self, server: &str) {
+        *lock(&self.queries_in_flight).entry(server.to_string()).or_default() += 1;
+    }
+
+    /// Records the end of a data source query.
+    pub fn end_query(&self, server: &str, duration: Duration, failed: bool) {
+        *lock(&self.queries_in_flight).entry(server.to_string()).or_default() -= 1;
+        lock(&self.query_durations).entry(server.to_string()).or_default().observe(duration);
+
+        if failed {
+            *lock(&self.query_errors).entry(server.to_string()).or_default() += 1;
+        }
+    }
+
+    /// Writes the recorded metrics.
+    pub fn write(&self, writer: &mut MetricsWriter) {
+        writer.family("panoptes_http_requests_total", "counter", "Requests served, per method, route and status.");
+        for ((method, route, status), count) in lock(&self.requests).iter() {
+            writer.sample("panoptes_http_requests_total", &[("method", method), ("route", route), ("status", &status.to_string())], *count as f64);
+        }
+
+        writer.family("panoptes_http_request_duration_seconds", "histogram", "Time spent serving requests, per method and route.");
+        for ((method, route), histogram) in lock(&self.request_durations).iter() {
+            writer.histogram("panoptes_http_request_duration_seconds", &[("method", method), ("route", route)], histogram);
+        }
+
+        writer.family("panoptes_source_query_duration_seconds", "histogram", "Time spent querying the data source, per server.");
+        for (server, histogram) in lock(&self.query_durations).iter() {
+            writer.histogram("panoptes_source_query_duration_seconds", &[("server", server)], histogram);
+        }
+
+        writer.family("panoptes_source_query_errors_total", "counter", "Failed data source queries, per server.");
+        for (server, count) in lock(&self.query_errors).iter() {
+            writer.sample("panoptes_source_query_errors_total", &[("server", server)], *count as f64);
+        }
+
+        writer.family("panoptes_source_queries_in_flight", "gauge", "Data source queries running, each holding a connection of the pool, per server.");
+        for (server, count) in lock(&self.queries_in_flight).iter() {
+            writer.sample("panoptes_source_queries_in_flight", &[("server", server)], *count as f64);
+        }
+    }
+}
+
+/// The net flow of every area, computed in the background at the configured interval, so that
+/// scrapes never query the data source.
+#[derive(Default)]
+pub(crate) struct AreaFlows {
+    /// The samples of each server: area, material (“all” for every item) and net flow.
+    flows: Mutex<BTreeMap<String, Vec<(String, String, f64)>>>
+}
+
+impl AreaFlows {
+    /// Starts the background task computing the flows of every server. A server whose flows
+    /// can't be computed keeps its previous ones.
+    pub fn start(self: Arc<Self>, servers: Servers, locale: Arc<MinecraftLocale>, values: ItemValues, config: MetricsConfigInner) {
+        let materials: Vec<String> = config.materials.iter()
+            .map(|material| material.strip_prefix("minecraft:").unwrap_or(material).to_lowercase())
+            .collect();
+
+        thread::spawn(move || loop {
+            for server in servers.iter() {
+                match area_flows(server, &materials, &locale, &values) {
+                    Ok(flows) => { lock(&self.flows).insert(server.id.clone(), flows); },
+                    Err(error) => eprintln!("Unable to compute the area flows of server {}: {}", server.id, error)
+                }
+            }
+
+            thread::sleep(Duration::from_secs(config.interval));
+        });
+    }
+
+    /// Writes the last computed flows.
+    pub fn write(&self, writer: &mut MetricsWriter) {
+        writer.family("panoptes_area_net_flow", "gauge", "Net flow of items in each area since the first record, per server, area and material (“all” for every item).");
+        for (server, flows) in lock(&self.flows).iter() {
+            for (area, material, flow) in flows.iter() {
+                writer.sample("panoptes_area_net_flow", &[("server", server), ("area", area), ("material", material)], *flow);
+            }
+        }
+    }
+}
+
+/// Computes the net flow of every area of a server, in total and for each of the `materials`.
+fn area_flows(server: &Server, materials: &[String], locale: &Arc<MinecraftLocale>, values: &ItemValues) -> Result<Vec<(String, String, f64)>, DataError> {
+    let areas = server.areas.get();
+    let filters = Filters::new(areas.areas.values().cloned().collect::<Vec<Area>>(), Uuids::any(), TimeBound::unbounded(), TimeBound::unbounded());
+
+    if filters.areas.is_empty() {
+        return Ok(vec![]);
+    }
+
+    // Like `/ratios`, flows come from the summary store if it's ready, and are cached.
+    let ratios = match server.summary.as_ref().filter(|summary| summary.is_ready(&areas)) {
+        Some(summary) => query_summary_ratios(summary, filters, GroupBy::Area, Arc::clone(locale), values.clone())?,
+        None => query_ratios(server.source.blocking(), &server.id, filters, GroupBy::Area, Arc::clone(locale), values.clone())?
+    };
+
+    let mut flows = vec![];
+
+    for (area, breakdown) in ratios.areas.unwrap_or_default() {
+        flows.push((area.clone(), String::from("all"), breakdown.totals.global as f64));
+
+        for ratio in breakdown.detail.iter() {
+            let material = ratio.id.strip_prefix("minecraft:").unwrap_or(&ratio.id);
+            if materials.iter().any(|m| m == material) {
+                flows.push((area.clone(), material.to_string(), ratio.flow.ratio as f64));
+            }
+        }
+    }
+
+    Ok(flows)
+}
+
+/// Each metric is updated in a single step, so values behind poisoned locks are consistent.
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner()
+    }
+}
+
+
+/// Writes metrics in the Prometheus text exposition format.
+#[derive(Default)]
+pub struct MetricsWriter {
+    output: String
+}
+
+impl MetricsWriter {
+    /// Starts a family of metrics, with its type and description.
+    pub fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.output, "# HELP {} {}", name, help);
+        let _ = writeln!(self.output, "# TYPE {} {}", name, kind);
+    }
+
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let labels = labels.iter()
+            .map(|(label, value)| format!("{}=\"{}\"", label, escape(value)))
+            .collect::<Vec<String>>()
+            .join(",");
+
+        let _ = match labels.is_empty() {
+            true => writeln!(self.output, "{} {}", name, value),
+            false => writeln!(self.output, "{}{{{}}} {}", name, labels, value)
+        };
+    }
+
+    fn histogram(&mut self, name: &str, labels: &[(&str, &str)], histogram: &Histogram) {
+        let bucket_name = format!("{}_bucket", name);
+
+        for (bound, count) in BUCKETS.iter().zip(histogram.counts.iter()) {
+            let bound = bound.to_string();
+            let labels: Vec<(&str, &str)> = labels.iter().cloned().chain(std::iter::once(("le", bound.as_str()))).collect();
+            self.sample(&bucket_name, &labels, *count as f64);
+        }
+
+        let labels_inf: Vec<(&str, &str)> = labels.iter().cloned().chain(std::iter::once(("le", "+Inf"))).collect();
+        self.sample(&bucket_name, &labels_inf, histogram.count as f64);
+        self.sample(&format!("{}_sum", name), labels, histogram.sum);
+        self.sample(&format!("{}_count", name), labels, histogram.count as f64);
+    }
+
+    pub fn finish(self) -> String {
+        self.output
+    }
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exposition_format() {
+        let mut histogram = Histogram::default();
+        for millis in &[250, 500, 20_000] {
+            histogram.observe(Duration::from_millis(*millis));
+        }
+
+        let mut writer = MetricsWriter::default();
+
+        writer.family("test_requests_total", "counter", "Requests served.");
+        writer.sample("test_requests_total", &[], 42.0);
+
+        writer.family("test_area_flow", "gauge", "Net flow per area.");
+        writer.sample("test_area_flow", &[("server", "survival"), ("area", "say \"hi\" \\ bye\n")], -1.5);
+
+        writer.family("test_duration_seconds", "histogram", "Time spent.");
+        writer.histogram("test_duration_seconds", &[("server", "survival")], &histogram);
+
+        assert_eq!(writer.finish(), [
+            "# HELP test_requests_total Requests served.",
+            "# TYPE test_requests_total counter",
+            "test_requests_total 42",
+            "# HELP test_area_flow Net flow per area.",
+            "# TYPE test_area_flow gauge",
+            r#"test_area_flow{server="survival",area="say \"hi\" \\ bye\n"} -1.5"#,
+            "# HELP test_duration_seconds Time spent.",
+            "# TYPE test_duration_seconds histogram",
+            r#"test_duration_seconds_bucket{server="survival",le="0.005"} 0"#,
+            r#"test_duration_seconds_bucket{server="survival",le="0.01"} 0"#,
+            r#"test_duration_seconds_bucket{server="survival",le="0.025"} 0"#,
+            r#"test_duration_seconds_bucket{server="survival",le="0.05"} 0"#,
+            r#"test_duration_seconds_bucket{server="survival",le="0.1"} 0"#,
+            r#"test_duration_seconds_bucket{server="survival",le="0.25"} 1"#,
+            r#"test_duration_seconds_bucket{server="survival",le="0.5"} 2"#,
+            r#"test_duration_seconds_bucket{server="survival",le="1"} 2"#,
+            r#"test_duration_seconds_bucket{server="survival",le="2.5"} 2"#,
+            r#"test_duration_seconds_bucket{server="survival",le="5"} 2"#,
+            r#"test_duration_seconds_bucket{server="survival",le="10"} 2"#,
+            r#"test_duration_seconds_bucket{server="survival",le="+Inf"} 3"#,
+            r#"test_duration_seconds_sum{server="survival"} 20.75"#,
+            r#"test_duration_seconds_count{server="survival"} 3"#,
+            ""
+        ].join("\n"));
+    }
+}
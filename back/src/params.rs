@@ -1,12 +1,17 @@
+use std::collections::HashMap;
 use std::fmt;
 
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, Utc};
 use itertools::Itertools;
 use rocket::http::RawStr;
+use serde::Serialize;
 use rocket::request::{FromParam, FromFormValue};
 use rocket_contrib::uuid::Uuid;
 
 use uuid::Uuid as UuidReal;
 use crate::area::Areas;
+use crate::query::Sql;
+use crate::source::Dialect;
 
 
 /// Represents a list of areas in a query string.
@@ -23,16 +28,29 @@ impl AreasIds {
             areas: vec![]
         }
     }
+
+    /// Lists the given areas (or groups), or every area if the list is empty.
+    pub fn list(areas: Vec<String>) -> Self {
+        AreasIds {
+            all: areas.is_empty(),
+            areas
+        }
+    }
 }
 
 impl Areas {
-    /// Filters out the areas, keeping only those matching the given list.
+    /// Filters out the areas, keeping only those matching the given list. Groups IDs in the list
+    /// are expanded to all their areas. The returned structure has no groups.
     pub fn filter(&self, list: AreasIds) -> Self {
+        let ids: Vec<String> = list.areas.iter().flat_map(|id| self.expand(id)).collect();
+
         Areas {
             areas: self.areas.iter()
-                .filter( | (id, _) | list.all || list.areas.contains(*id))
+                .filter( | (id, _) | list.all || ids.contains(*id))
                 .map(|(id, area)| (id.clone(), area.clone()))
-                .collect()
+                .collect(),
+            groups: HashMap::new(),
+            roots: vec![]
         }
     }
 }
@@ -60,19 +78,26 @@ impl<'v> FromFormValue<'v> for AreasIds {
 
 
 /// Represents a list of UUIDs in a query string.
+#[derive(Debug, Clone)]
 pub struct Uuids {
     pub uuids: Vec<Uuid>
 }
 
 impl Uuids {
 
-    /// Generates an SQL WHERE clause to filter for these UUIDs, assuming that the `prism_players`
-    /// table is aliased as `p`.
-    pub fn as_sql(&self) -> String {
-        self.uuids.iter()
-            .map(|uuid| format!("HEX(p.player_uuid) = '{}'", uuid.to_simple().encode_lower(&mut UuidReal::encode_buffer())))
-            .intersperse(String::from(" OR "))
-            .collect()
+    /// An empty list of UUIDs, matching every player.
+    pub fn any() -> Self {
+        Uuids { uuids: vec![] }
+    }
+
+    /// Generates an SQL WHERE clause to filter for these UUIDs, given a column containing
+    /// upper-case hex-encoded UUIDs. An empty list matches every player.
+    pub fn as_sql(&self, column: &str) -> Sql {
+        if self.uuids.is_empty() {
+            return Sql::new("TRUE");
+        }
+
+        Sql::in_list(column, self.uuids.iter().map(|uuid| uuid.to_simple().to_string().to_uppercase()).collect())
     }
 }
 
@@ -110,3 +135,272 @@ impl fmt::Display for Uuids {
         )
     }
 }
+
+
+/// Represents a point in time in a query string, used to restrict results to a time window.
+///
+/// It can be given as a UNIX timestamp (`1605571200`), an ISO-8601 date or date-time
+/// (`2020-11-17`, `2020-11-17T00:00:00Z`), or relatively to the current time (`30d` for
+/// thirty days ago). Supported relative units are `s`, `m` (minutes), `h`, `d` and `w`.
+/// `unbounded` is used when the query string is missing.
+#[derive(Debug, Clone)]
+pub struct TimeBound {
+    pub epoch: Option<i64>,
+    relative: Option<String>
+}
+
+impl TimeBound {
+    pub fn unbounded() -> Self {
+        TimeBound {
+            epoch: None,
+            relative: None
+        }
+    }
+
+    pub fn at(epoch: i64) -> Self {
+        TimeBound {
+            epoch: Some(epoch),
+            relative: None
+        }
+    }
+
+    /// Parses a time bound from its textual representation. See [`TimeBound`] for the
+    /// accepted formats.
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        let raw = raw.trim();
+
+        if raw.is_empty() {
+            return Ok(Self::unbounded());
+        }
+
+        if let Ok(epoch) = raw.parse::<i64>() {
+            return Ok(TimeBound { epoch: Some(epoch), relative: None });
+        }
+
+        if let Some(duration) = parse_relative_duration(raw) {
+            return Ok(TimeBound {
+                epoch: Some((Utc::now() - duration).timestamp()),
+                relative: Some(raw.to_lowercase())
+            });
+        }
+
+        if let Ok(date_time) = DateTime::parse_from_rfc3339(raw) {
+            return Ok(TimeBound { epoch: Some(date_time.timestamp()), relative: None });
+        }
+
+        if let Ok(date_time) = NaiveDateTime::parse_from_str(raw, "%Y-%m-%dT%H:%M:%S") {
+            return Ok(TimeBound { epoch: Some(date_time.timestamp()), relative: None });
+        }
+
+        if let Ok(date) = NaiveDate::parse_from_str(raw, "%Y-%m-%d") {
+            return Ok(TimeBound { epoch: Some(date.and_hms(0, 0, 0).timestamp()), relative: None });
+        }
+
+        Err(format!("Invalid date: {}", raw))
+    }
+
+    /// Rounds this bound to the beginning of its day (UTC), or to the beginning of the next day
+    /// if `up` is set and the bound is not already at midnight.
+    pub fn round_to_day(&self, up: bool) -> Self {
+        const DAY: i64 = 24 * 3600;
+
+        TimeBound {
+            epoch: self.epoch.map(|epoch| match up && epoch.rem_euclid(DAY) != 0 {
+                true => (epoch.div_euclid(DAY) + 1) * DAY,
+                false => epoch.div_euclid(DAY) * DAY
+            }),
+            relative: None
+        }
+    }
+}
+
+/// Parses a relative duration like `30d` or `12h`. Returns `None` if the input is not a
+/// relative duration.
+fn parse_relative_duration(raw: &str) -> Option<Duration> {
+    let raw = raw.to_lowercase();
+    let unit = raw.chars().last()?;
+    let amount = raw[..raw.len() - unit.len_utf8()].parse::<i64>().ok()?;
+
+    match unit {
+        's' => Some(Duration::seconds(amount)),
+        'm' => Some(Duration::minutes(amount)),
+        'h' => Some(Duration::hours(amount)),
+        'd' => Some(Duration::days(amount)),
+        'w' => Some(Duration::weeks(amount)),
+        _ => None
+    }
+}
+
+impl<'v> FromFormValue<'v> for TimeBound {
+    type Error = String;
+
+    /// Implements the conversion from a query string (or the lack of one) to a time bound.
+    #[inline(always)]
+    fn from_form_value(param: &'v RawStr) -> Result<Self, Self::Error> {
+        Self::parse(&param.url_decode_lossy())
+    }
+
+    #[inline(always)]
+    fn default() -> Option<Self> {
+        Some(Self::unbounded())
+    }
+}
+
+impl fmt::Display for TimeBound {
+
+    /// The formatted version is used as a cache key. Relative bounds are displayed as
+    /// written, so that `30d` hits the cache regardless of the current second.
+    #[inline(always)]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.relative, self.epoch) {
+            (Some(relative), _) => write!(f, "{}", relative),
+            (None, Some(epoch)) => write!(f, "{}", epoch),
+            (None, None) => write!(f, "-")
+        }
+    }
+}
+
+
+/// Represents the size of the buckets of a timeline in a query string. Defaults to `day`.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Bucket {
+    Hour,
+    Day,
+    Week,
+    Month
+}
+
+impl Bucket {
+
+    /// Generates an SQL expression computing the timestamp of the beginning of the bucket
+    /// containing the given epoch column. Weeks start on Monday; months are computed in the
+    /// database server's timezone with MySQL, and in UTC with SQLite.
+    pub fn as_sql(&self, column: &str, dialect: Dialect) -> String {
+        match (self, dialect) {
+            (Bucket::Hour, _) => format!("({0} - {0} % 3600)", column),
+            (Bucket::Day, _) => format!("({0} - {0} % 86400)", column),
+            // The UNIX epoch was a Thursday, so we shift by four days to align weeks on Mondays.
+            (Bucket::Week, _) => format!("({0} - ({0} - 345600) % 604800)", column),
+            (Bucket::Month, Dialect::MySql) => format!("UNIX_TIMESTAMP(DATE_FORMAT(FROM_UNIXTIME({}), '%Y-%m-01'))", column),
+            (Bucket::Month, Dialect::Sqlite) => format!("CAST(strftime('%s', {}, 'unixepoch', 'start of month') AS INTEGER)", column)
+        }
+    }
+}
+
+impl<'v> FromFormValue<'v> for Bucket {
+    type Error = &'v RawStr;
+
+    /// Implements the conversion from a query string (or the lack of one) to a bucket size.
+    #[inline(always)]
+    fn from_form_value(param: &'v RawStr) -> Result<Self, Self::Error> {
+        match param.as_str().to_lowercase().as_str() {
+            "hour" => Ok(Bucket::Hour),
+            "day" => Ok(Bucket::Day),
+            "week" => Ok(Bucket::Week),
+            "month" => Ok(Bucket::Month),
+            _ => Err(param)
+        }
+    }
+
+    #[inline(always)]
+    fn default() -> Option<Self> {
+        Some(Bucket::Day)
+    }
+}
+
+
+/// Represents how `/ratios` results should be broken down, in a query string. Whatever the
+/// grouping, the combined total is always returned. Defaults to `none`.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupBy {
+    None,
+    Player,
+    Area
+}
+
+impl<'v> FromFormValue<'v> for GroupBy {
+    type Error = &'v RawStr;
+
+    /// Implements the conversion from a query string (or the lack of one) to a grouping mode.
+    #[inline(always)]
+    fn from_form_value(param: &'v RawStr) -> Result<Self, Self::Error> {
+        match param.as_str().to_lowercase().as_str() {
+            "none" | "" => Ok(GroupBy::None),
+            "player" => Ok(GroupBy::Player),
+            "area" => Ok(GroupBy::Area),
+            _ => Err(param)
+        }
+    }
+
+    #[inline(always)]
+    fn default() -> Option<Self> {
+        Some(GroupBy::None)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_absolute_time_bounds() {
+        assert_eq!(TimeBound::parse("1605571200").unwrap().epoch, Some(1605571200));
+        assert_eq!(TimeBound::parse("-3600").unwrap().epoch, Some(-3600));
+        assert_eq!(TimeBound::parse("2020-11-17").unwrap().epoch, Some(1605571200));
+        assert_eq!(TimeBound::parse("2020-11-17T18:30:00").unwrap().epoch, Some(1605637800));
+        assert_eq!(TimeBound::parse("2020-11-17T18:30:00Z").unwrap().epoch, Some(1605637800));
+        assert_eq!(TimeBound::parse("2020-11-17T19:30:00+01:00").unwrap().epoch, Some(1605637800));
+        assert_eq!(TimeBound::parse("  2020-11-17 ").unwrap().epoch, Some(1605571200));
+    }
+
+    #[test]
+    fn parse_relative_time_bounds() {
+        for (raw, seconds) in &[("30s", 30), ("15m", 900), ("12h", 43200), ("30d", 2592000), ("2W", 1209600)] {
+            let bound = TimeBound::parse(raw).unwrap();
+            let expected = Utc::now().timestamp() - seconds;
+
+            assert!((bound.epoch.unwrap() - expected).abs() <= 1, "{}", raw);
+            assert_eq!(bound.relative, Some(raw.to_lowercase()));
+        }
+    }
+
+    #[test]
+    fn parse_unbounded_time_bounds() {
+        assert_eq!(TimeBound::parse("").unwrap().epoch, None);
+        assert_eq!(TimeBound::parse("   ").unwrap().epoch, None);
+    }
+
+    #[test]
+    fn parse_invalid_time_bounds() {
+        for raw in &["yesterday", "12x", "d", "2020-13-01", "2020-11-17T25:00:00", "17/11/2020"] {
+            assert_eq!(TimeBound::parse(raw).unwrap_err(), format!("Invalid date: {}", raw));
+        }
+    }
+
+    #[test]
+    fn round_time_bounds_to_day() {
+        let day = 1605571200;
+
+        assert_eq!(TimeBound::at(day + 3600).round_to_day(false).epoch, Some(day));
+        assert_eq!(TimeBound::at(day + 3600).round_to_day(true).epoch, Some(day + 86400));
+        assert_eq!(TimeBound::at(day).round_to_day(true).epoch, Some(day));
+        assert_eq!(TimeBound::at(-3600).round_to_day(false).epoch, Some(-86400));
+        assert_eq!(TimeBound::unbounded().round_to_day(true).epoch, None);
+    }
+
+    #[test]
+    fn uuids_sql() {
+        assert_eq!(Uuids::any().as_sql("h.player_uuid").text(), "TRUE");
+
+        let uuids = Uuids {
+            uuids: vec![Uuid::from_param("069a79f4-44e9-4726-a5be-fca90e38aaf5".into()).unwrap()]
+        };
+        let sql = uuids.as_sql("h.player_uuid");
+
+        assert_eq!(sql.text(), "h.player_uuid IN (?)");
+        assert_eq!(sql.values(), &[mysql::Value::Bytes(b"069A79F444E94726A5BEFCA90E38AAF5".to_vec())][..]);
+    }
+}
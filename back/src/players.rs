@@ -0,0 +1,159 @@
+use uuid::Uuid;
+
+use crate::config::{PlayersConfig, PlayersConfigInner};
+use crate::query::Sql;
+use crate::values::glob_matches;
+
+
+/// Rules used to exclude non-player entries from the data source's players (Prism records
+/// entities and causes like `zombie` or `Lava` as players, CoreProtect as `#zombie`), so that
+/// they are not listed as players.
+///
+/// An entry is excluded if its name is one of the excluded names, if its name matches one of the
+/// excluded patterns (`*` matches any characters, `?` exactly one), or if its UUID version is not
+/// one of the allowed ones (online-mode players have version 4 UUIDs, offline-mode ones have
+/// version 3 UUIDs). Names are compared case-insensitively.
+#[derive(Debug, Clone, Default)]
+pub struct PlayerExclusions {
+    names: Vec<String>,
+    patterns: Vec<String>,
+    uuid_versions: Vec<usize>
+}
+
+impl PlayerExclusions {
+    /// Returns the rule excluding the given entry, if any, formatted as `<kind>:<rule>`.
+    pub fn rule_for(&self, name: &str, uuid: &Uuid) -> Option<String> {
+        let name = name.to_lowercase();
+
+        if self.names.contains(&name) {
+            return Some(format!("name:{}", name));
+        }
+
+        if let Some(pattern) = self.patterns.iter().find(|pattern| glob_matches(pattern, &name)) {
+            return Some(format!("pattern:{}", pattern));
+        }
+
+        if !self.uuid_versions.is_empty() && !self.uuid_versions.contains(&uuid.get_version_num()) {
+            return Some(format!("uuid_version:{}", uuid.get_version_num()));
+        }
+
+        None
+    }
+
+    /// Generates an SQL WHERE clause excluding non-player entries, given the columns containing
+    /// the player name and their hex-encoded UUID.
+    pub fn as_sql(&self, name_column: &str, uuid_column: &str) -> Sql {
+        let mut clauses = vec![];
+
+        if !self.names.is_empty() {
+            clauses.push(Sql::compose("NOT {}", vec![Sql::in_list(&format!("LOWER({})", name_column), self.names.clone())]));
+        }
+
+        for pattern in self.patterns.iter() {
+            clauses.push(Sql::new(format!("NOT LOWER({}) LIKE ? ESCAPE '!'", name_column)).bind(glob_to_like(pattern)));
+        }
+
+        if !self.uuid_versions.is_empty() {
+            // The version is the 13th hexadecimal digit of the UUID.
+            clauses.push(Sql::in_list(
+                &format!("SUBSTR({}, 13, 1)", uuid_column),
+                self.uuid_versions.iter().map(|version| format!("{:X}", version)).collect()
+            ));
+        }
+
+        Sql::and(clauses)
+    }
+}
+
+impl From<PlayersConfig> for PlayerExclusions {
+    fn from(config: PlayersConfig) -> Self {
+        config.players.into()
+    }
+}
+
+impl From<PlayersConfigInner> for PlayerExclusions {
+    fn from(config: PlayersConfigInner) -> Self {
+        PlayerExclusions {
+            names: config.exclude.into_iter().map(|name| name.to_lowercase()).collect(),
+            patterns: config.exclude_patterns.into_iter().map(|pattern| pattern.to_lowercase()).collect(),
+            uuid_versions: config.uuid_versions
+        }
+    }
+}
+
+/// Converts a glob pattern to a `LIKE` pattern, escaping `LIKE` special characters with `!`
+/// (the backslash is not a portable escape character).
+fn glob_to_like(pattern: &str) -> String {
+    pattern.chars()
+        .map(|c| match c {
+            '*' => String::from("%"),
+            '?' => String::from("_"),
+            '%' | '_' | '!' => format!("!{}", c),
+            c => c.to_string()
+        })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use mysql::Value;
+
+    use super::*;
+
+    fn exclusions(config: &str) -> PlayerExclusions {
+        toml::from_str::<PlayersConfig>(config).expect("invalid players configuration").into()
+    }
+
+    #[test]
+    fn default_exclusions() {
+        let uuid = Uuid::new_v4();
+
+        for config in &["", "[players]\nuuid_versions = []"] {
+            let exclusions = exclusions(config);
+
+            assert_eq!(exclusions.rule_for("Zombie", &uuid), Some(String::from("name:zombie")));
+            assert_eq!(exclusions.rule_for("lava", &uuid), Some(String::from("name:lava")));
+            assert_eq!(exclusions.rule_for("minecraft:creeper", &uuid), Some(String::from("pattern:*:*")));
+            assert_eq!(exclusions.rule_for("#tnt", &uuid), Some(String::from("pattern:#*")));
+            assert_eq!(exclusions.rule_for("Notch", &uuid), None);
+        }
+    }
+
+    #[test]
+    fn configured_exclusions_replace_defaults() {
+        let exclusions = exclusions("[players]\nexclude = [\"Herobrine\"]\nexclude_patterns = []");
+        let uuid = Uuid::new_v4();
+
+        assert_eq!(exclusions.rule_for("herobrine", &uuid), Some(String::from("name:herobrine")));
+        assert_eq!(exclusions.rule_for("zombie", &uuid), None);
+        assert_eq!(exclusions.rule_for("#tnt", &uuid), None);
+    }
+
+    #[test]
+    fn exclusions_sql() {
+        let exclusions = exclusions("[players]\nexclude = [\"Zombie\"]\nexclude_patterns = [\"#*\", \"100%_*\"]\nuuid_versions = [4]");
+        let sql = exclusions.as_sql("h.player_name", "h.player_uuid");
+
+        assert_eq!(
+            sql.text(),
+            "(NOT LOWER(h.player_name) IN (?)) \
+            AND (NOT LOWER(h.player_name) LIKE ? ESCAPE '!') \
+            AND (NOT LOWER(h.player_name) LIKE ? ESCAPE '!') \
+            AND (SUBSTR(h.player_uuid, 13, 1) IN (?))"
+        );
+        assert_eq!(sql.values(), &[
+            Value::Bytes(b"zombie".to_vec()),
+            Value::Bytes(b"#%".to_vec()),
+            Value::Bytes(b"100!%!_%".to_vec()),
+            Value::Bytes(b"4".to_vec())
+        ][..]);
+    }
+
+    #[test]
+    fn no_exclusions_sql() {
+        let exclusions = exclusions("[players]\nexclude = []\nexclude_patterns = []\nuuid_versions = []");
+
+        assert_eq!(exclusions.as_sql("h.player_name", "h.player_uuid").text(), "TRUE");
+    }
+}
@@ -0,0 +1,291 @@
+use std::fmt;
+
+use itertools::Itertools;
+use mysql::{Params, Value};
+
+use crate::area::{Area, cache_key_for_vec_areas, sort_by_specificity};
+use crate::params::{TimeBound, Uuids};
+use crate::players::PlayerExclusions;
+use crate::source::HistoryRow;
+
+
+/// A fragment of SQL where every user-supplied value is a `?` placeholder, alongside the values
+/// bound to these placeholders, in order.
+///
+/// Fragments are composed together (see [`Sql::compose`], [`Sql::and`] and [`Sql::or`]) so that
+/// values are never interpolated into the query text: queries are injection-safe by construction,
+/// and the same text can be reused as a prepared statement by MySQL whatever the values.
+#[derive(Debug, Clone, Default)]
+pub struct Sql {
+    text: String,
+    values: Vec<Value>
+}
+
+impl Sql {
+    /// Creates a fragment from a static text. Placeholders must then be bound with [`Sql::bind`].
+    pub fn new<S: Into<String>>(text: S) -> Self {
+        Sql {
+            text: text.into(),
+            values: vec![]
+        }
+    }
+
+    /// Binds a value to the next placeholder of this fragment.
+    pub fn bind<V: Into<Value>>(mut self, value: V) -> Self {
+        self.values.push(value.into());
+        self
+    }
+
+    /// Builds a fragment from a template where each `{}` is replaced by the corresponding
+    /// fragment. Values are kept in the order of the template.
+    ///
+    /// Panics if the number of `{}` doesn't match the number of fragments, as the extra text or
+    /// values would otherwise be silently dropped.
+    pub fn compose(template: &str, fragments: Vec<Sql>) -> Self {
+        assert_eq!(
+            template.matches("{}").count(), fragments.len(),
+            "mismatched placeholders in SQL template {:?}", template
+        );
+
+        let mut parts = template.split("{}");
+        let mut sql = Sql::new(parts.next().unwrap_or_default());
+
+        for (part, fragment) in parts.zip(fragments.into_iter()) {
+            sql.text.push_str(&fragment.text);
+            sql.text.push_str(part);
+            sql.values.extend(fragment.values);
+        }
+
+        sql
+    }
+
+    /// Joins fragments with `AND`. An empty list of fragments matches everything.
+    pub fn and(fragments: Vec<Sql>) -> Self {
+        Self::join(fragments, " AND ", "TRUE")
+    }
+
+    /// Joins fragments with `OR`. An empty list of fragments matches nothing.
+    pub fn or(fragments: Vec<Sql>) -> Self {
+        Self::join(fragments, " OR ", "FALSE")
+    }
+
+    /// Generates a `column IN (…)` fragment, each value being bound.
+    pub fn in_list<V: Into<Value>>(column: &str, values: Vec<V>) -> Self {
+        if values.is_empty() {
+            return Sql::new("FALSE");
+        }
+
+        let placeholders: String = values.iter().map(|_| "?").intersperse(", ").collect();
+
+        Sql {
+            text: format!("{} IN ({})", column, placeholders),
+            values: values.into_iter().map(Into::into).collect()
+        }
+    }
+
+    fn join(fragments: Vec<Sql>, separator: &str, empty: &str) -> Self {
+        if fragments.is_empty() {
+            return Sql::new(empty);
+        }
+
+        let template: String = fragments.iter().map(|_| "({})").intersperse(separator).collect();
+
+        Self::compose(&template, fragments)
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The bound values, in order.
+    pub fn values(&self) -> &[Value] {
+        &self.values
+    }
+
+    /// The bound values, to be used to execute the statement.
+    pub fn params(&self) -> Params {
+        match self.values.len() {
+            0 => Params::Empty,
+            _ => Params::Positional(self.values.clone())
+        }
+    }
+}
+
+
+/// The filters that can be applied to container transactions. Empty lists of players or
+/// materials and unbounded times match everything; an empty list of areas matches nothing.
+///
+/// If `exclusions` are set, non-player entries are filtered out. As these come from the
+/// configuration, they are not part of the cache key.
+#[derive(Debug, Clone)]
+pub struct Filters {
+    /// Sorted by specificity (see [`sort_by_specificity`]): a transaction in overlapping areas
+    /// is attributed to the first one.
+    pub areas: Vec<Area>,
+    pub players: Uuids,
+    pub since: TimeBound,
+    pub until: TimeBound,
+    pub materials: Vec<String>,
+    pub exclusions: Option<PlayerExclusions>
+}
+
+impl Filters {
+    pub fn new(mut areas: Vec<Area>, players: Uuids, since: TimeBound, until: TimeBound) -> Self {
+        sort_by_specificity(&mut areas);
+
+        Filters {
+            areas,
+            players,
+            since,
+            until,
+            materials: vec![],
+            exclusions: None
+        }
+    }
+
+    /// Generates an SQL WHERE clause to apply these filters, assuming that the container
+    /// history rows (see `crate::source`) are aliased as `h`.
+    pub fn as_sql(&self) -> Sql {
+        Sql::and(vec![
+            Sql::or(self.areas.iter().map(|area| area.as_sql()).collect()),
+            self.players.as_sql("h.player_uuid"),
+            self.time_window_as_sql(),
+            self.materials_as_sql(),
+            self.exclusions.as_ref()
+                .map(|exclusions| exclusions.as_sql("h.player_name", "h.player_uuid"))
+                .unwrap_or(Sql::new("TRUE"))
+        ])
+    }
+
+    /// Generates an SQL WHERE clause to restrict results to the time window. The lower bound is
+    /// inclusive, the upper one exclusive.
+    fn time_window_as_sql(&self) -> Sql {
+        let mut clauses = vec![];
+
+        if let Some(since) = self.since.epoch {
+            clauses.push(Sql::new("h.epoch >= ?").bind(since));
+        }
+
+        if let Some(until) = self.until.epoch {
+            clauses.push(Sql::new("h.epoch < ?").bind(until));
+        }
+
+        Sql::and(clauses)
+    }
+
+    /// Returns the first area where a raw transaction matches these filters, or `None` if it
+    /// doesn't match them. Same rules as [`Filters::as_sql`], except that exclusions are ignored.
+    pub fn area_of(&self, row: &HistoryRow) -> Option<&Area> {
+        let player_matches = self.players.uuids.is_empty() || self.players.uuids.iter()
+            .any(|uuid| uuid.to_simple().to_string().eq_ignore_ascii_case(&row.player_uuid));
+
+        let time_matches = self.since.epoch.map(|since| row.epoch >= since).unwrap_or(true)
+            && self.until.epoch.map(|until| row.epoch < until).unwrap_or(true);
+
+        let material_matches = self.materials.is_empty() || self.materials.iter()
+            .any(|material| material.strip_prefix("minecraft:").unwrap_or(material) == row.material);
+
+        if !(player_matches && time_matches && material_matches) {
+            return None;
+        }
+
+        self.areas.iter().find(|area| area.contains(&row.world, row.x, row.y, row.z))
+    }
+
+    fn materials_as_sql(&self) -> Sql {
+        match self.materials.len() {
+            0 => Sql::new("TRUE"),
+            _ => Sql::in_list(
+                "h.material",
+                self.materials.iter()
+                    .map(|material| material.strip_prefix("minecraft:").unwrap_or(material).to_string())
+                    .collect()
+            )
+        }
+    }
+}
+
+impl fmt::Display for Filters {
+
+    /// The formatted version is used as a cache key. For any identical set of filters the output
+    /// should be the same.
+    #[inline(always)]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f, "{}|{}|{}|{}|{}",
+            cache_key_for_vec_areas(&self.areas),
+            self.players,
+            self.since,
+            self.until,
+            self.materials.iter().sorted().join(",")
+        )
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compose_keeps_values_in_order() {
+        let sql = Sql::compose("SELECT ? FROM t WHERE {} AND ({})", vec![
+            Sql::new("a = ? OR b = ?").bind(2).bind(3),
+            Sql::new("c = ?").bind("four")
+        ]);
+
+        assert_eq!(sql.text(), "SELECT ? FROM t WHERE a = ? OR b = ? AND (c = ?)");
+        assert_eq!(sql.values(), &[Value::Int(2), Value::Int(3), Value::Bytes(b"four".to_vec())][..]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn compose_rejects_missing_fragments() {
+        Sql::compose("{} AND {}", vec![Sql::new("TRUE")]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn compose_rejects_extra_fragments() {
+        Sql::compose("{}", vec![Sql::new("TRUE"), Sql::new("a = ?").bind(1)]);
+    }
+
+    #[test]
+    fn and_or() {
+        assert_eq!(Sql::and(vec![]).text(), "TRUE");
+        assert_eq!(Sql::or(vec![]).text(), "FALSE");
+
+        let and = Sql::and(vec![Sql::new("a = ?").bind(1), Sql::new("b = ? OR c = ?").bind(2).bind(3)]);
+        assert_eq!(and.text(), "(a = ?) AND (b = ? OR c = ?)");
+        assert_eq!(and.values(), &[Value::Int(1), Value::Int(2), Value::Int(3)][..]);
+
+        let or = Sql::or(vec![and, Sql::new("d = ?").bind(4)]);
+        assert_eq!(or.text(), "((a = ?) AND (b = ? OR c = ?)) OR (d = ?)");
+        assert_eq!(or.values(), &[Value::Int(1), Value::Int(2), Value::Int(3), Value::Int(4)][..]);
+        assert_eq!(or.params(), Params::Positional(or.values().to_vec()));
+    }
+
+    #[test]
+    fn in_list() {
+        let empty = Sql::in_list::<i64>("h.id", vec![]);
+        assert_eq!(empty.text(), "FALSE");
+        assert_eq!(empty.params(), Params::Empty);
+
+        let list = Sql::in_list("h.material", vec!["stone", "dirt"]);
+        assert_eq!(list.text(), "h.material IN (?, ?)");
+        assert_eq!(list.values(), &[Value::Bytes(b"stone".to_vec()), Value::Bytes(b"dirt".to_vec())][..]);
+    }
+
+    #[test]
+    fn filters_time_window() {
+        let mut filters = Filters::new(vec![], Uuids::any(), TimeBound::at(100), TimeBound::at(200));
+        filters.materials = vec![String::from("minecraft:stone")];
+
+        let sql = filters.as_sql();
+        assert_eq!(
+            sql.text(),
+            "(FALSE) AND (TRUE) AND ((h.epoch >= ?) AND (h.epoch < ?)) AND (h.material IN (?)) AND (TRUE)"
+        );
+        assert_eq!(sql.values(), &[Value::Int(100), Value::Int(200), Value::Bytes(b"stone".to_vec())][..]);
+    }
+}
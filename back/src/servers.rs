@@ -0,0 +1,143 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::area_store::AreaStore;
+use crate::config::{AreasConfig, ServerConfig, ServersConfig, SourceConfig, SourceKind, SummaryConfigInner};
+use crate::source::Source;
+use crate::summary::Summary;
+
+
+/// The ID of the server described by the top-level `source` and `areas` sections, when no
+/// `servers` are configured.
+pub const DEFAULT_SERVER: &str = "default";
+
+/// A Minecraft server: its data source, its areas, and its summary store if enabled.
+#[derive(Clone)]
+pub(crate) struct Server {
+    pub id: String,
+    pub name: String,
+    pub source: Source,
+    pub areas: Arc<AreaStore>,
+    pub summary: Option<Arc<Summary>>
+}
+
+/// A server, as listed by the `/servers` endpoint.
+#[derive(Serialize, Debug, Clone)]
+pub struct ServerInfo {
+    pub id: String,
+    pub name: String,
+    pub kind: SourceKind
+}
+
+/// The servers served by this instance, made available through a state.
+#[derive(Clone)]
+pub(crate) struct Servers {
+    servers: BTreeMap<String, Server>
+}
+
+impl Servers {
+    /// Connects to the data source and loads the areas of every configured server, and starts
+    /// their summary ingesters if enabled. If there are no `servers`, a single one is built from
+    /// the top-level `source` and `areas` sections.
+    pub fn load(servers: ServersConfig, areas: AreasConfig, source: SourceConfig, summary: SummaryConfigInner) -> Result<Self, Vec<String>> {
+        let configs: Vec<(String, ServerConfig)> = match servers.servers.is_empty() {
+            true => vec![(String::from(DEFAULT_SERVER), ServerConfig {
+                name: None,
+                source: source.resolve(),
+                areas: areas.areas,
+                areas_overlay: Some(areas.areas_overlay)
+            })],
+            false => servers.servers.into_iter().collect()
+        };
+
+        if summary.enabled {
+            if let Err(e) = fs::create_dir_all(&summary.directory) {
+                return Err(vec![format!("Unable to create summaries directory {:?}: {}", summary.directory, e)]);
+            }
+        }
+
+        let mut loaded = BTreeMap::new();
+        let mut errors = vec![];
+
+        for (id, config) in configs {
+            // IDs are used as a path segment of the routes.
+            if id.is_empty() || id.contains('/') {
+                errors.push(format!("server {}: IDs must not be empty nor contain slashes", id));
+                continue;
+            }
+
+            let overlay_path = config.areas_overlay
+                .unwrap_or_else(|| PathBuf::from(format!("../areas-{}.toml", id)));
+
+            let areas = match AreaStore::load(config.areas, overlay_path) {
+                Ok(areas) => areas,
+                Err(area_errors) => {
+                    errors.extend(area_errors.into_iter().map(|error| format!("server {}: {}", id, error)));
+                    continue;
+                }
+            };
+
+            let source = match Source::connect(&id, config.source) {
+                Ok(source) => source,
+                Err(error) => {
+                    errors.push(format!("server {}: {}", id, error));
+                    continue;
+                }
+            };
+
+            let summary_store = match summary.enabled {
+                true => match Summary::open(summary.directory.join(format!("{}.db", id))) {
+                    Ok(summary_store) => Some(Arc::new(summary_store)),
+                    Err(error) => {
+                        errors.push(format!("server {}: unable to open summary store: {}", id, error));
+                        continue;
+                    }
+                },
+                false => None
+            };
+
+            loaded.insert(id.clone(), Server {
+                name: config.name.unwrap_or_else(|| id.clone()),
+                id,
+                source,
+                areas: Arc::new(areas),
+                summary: summary_store
+            });
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        // Ingesters are only started once every server is valid.
+        for server in loaded.values() {
+            if let Some(summary_store) = &server.summary {
+                Arc::clone(summary_store).start(server.source.clone(), Arc::clone(&server.areas), summary.clone());
+            }
+        }
+
+        Ok(Servers { servers: loaded })
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Server> {
+        self.servers.get(id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Server> {
+        self.servers.values()
+    }
+
+    pub fn list(&self) -> Vec<ServerInfo> {
+        self.servers.values()
+            .map(|server| ServerInfo {
+                id: server.id.clone(),
+                name: server.name.clone(),
+                kind: server.source.kind
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,175 @@
+use uuid::Uuid;
+
+use crate::database::Player;
+use crate::players::PlayerExclusions;
+use crate::query::{Filters, Sql};
+use super::{DataError, DataSource, Database, FlowRow, Grouping, HistoryRow, PlayerActivity, TransactionRow};
+use super::{query_flows, query_history, query_transactions, read_activity, read_players};
+
+
+/// The tables read from CoreProtect, without their prefix.
+const TABLES: [&str; 6] = ["container", "user", "world", "material_map", "session", "block"];
+
+/// Reads data from [CoreProtect](https://github.com/PlayPro/CoreProtect), stored either in
+/// MySQL or in SQLite (its default `database.db` file).
+///
+/// CoreProtect references users, worlds and materials by ID, and stores actions as numbers.
+/// They are translated to the names used by Prism (e.g. `item-insert`), so that the API is the
+/// same whatever the source.
+pub struct CoreProtect {
+    database: Database,
+
+    /// The prefix of the tables, `co_` by default.
+    prefix: String
+}
+
+impl CoreProtect {
+    pub fn new(database: Database, prefix: String) -> Self {
+        CoreProtect { database, prefix }
+    }
+
+    /// Replaces the `{prefix}` placeholders of a query with the tables prefix.
+    fn tables(&self, sql: &str) -> String {
+        sql.replace("{prefix}", &self.prefix)
+    }
+
+    /// The container history (see `crate::source`). Rolled back transactions are ignored.
+    fn container_history_sql(&self) -> Sql {
+        Sql::new(self.tables("
+            SELECT
+                c.rowid AS id,
+                CASE WHEN c.action = 1 THEN 'item-insert' ELSE 'item-remove' END AS action,
+                REPLACE(COALESCE(m.material, ''), 'minecraft:', '') AS material,
+                CASE WHEN c.action = 1 THEN c.amount ELSE -c.amount END AS amount_diff,
+                c.time AS epoch,
+                c.x AS x,
+                c.y AS y,
+                c.z AS z,
+                COALESCE(w.world, '') AS world,
+                UPPER(REPLACE(COALESCE(u.uuid, ''), '-', '')) AS player_uuid,
+                COALESCE(u.user, '') AS player_name
+            FROM {prefix}container c
+            LEFT JOIN {prefix}user u ON u.rowid = c.user
+            LEFT JOIN {prefix}world w ON w.id = c.wid
+            LEFT JOIN {prefix}material_map m ON m.id = c.type
+            WHERE c.rolled_back = 0
+        "))
+    }
+
+    /// Every block and container action of a user, with the action name, its time and world ID.
+    fn user_activity_sql(&self, user_id: i64) -> Sql {
+        Sql::new(self.tables("
+            SELECT
+                CASE action
+                    WHEN 0 THEN 'block-break'
+                    WHEN 1 THEN 'block-place'
+                    WHEN 2 THEN 'block-use'
+                    ELSE 'entity-kill'
+                END AS action,
+                time,
+                wid
+            FROM {prefix}block
+            WHERE user = ?
+            UNION ALL
+            SELECT
+                CASE WHEN action = 1 THEN 'item-insert' ELSE 'item-remove' END AS action,
+                time,
+                wid
+            FROM {prefix}container
+            WHERE user = ?
+        ")).bind(user_id).bind(user_id)
+    }
+}
+
+impl DataSource for CoreProtect {
+    fn players(&self, filter: &str, exclusions: Option<&PlayerExclusions>, limit: usize) -> Result<Vec<Player>, DataError> {
+        let exclusions_clause = match exclusions {
+            Some(exclusions) => exclusions.as_sql("u.user", "UPPER(REPLACE(COALESCE(u.uuid, ''), '-', ''))"),
+            None => Sql::new("TRUE")
+        };
+
+        // The last session of a user is their last connection; users who never connected (e.g.
+        // `#creeper`) fall back to the time they were first seen.
+        let sql = Sql::compose(
+            &self.tables("
+            SELECT
+                u.user AS name,
+                UPPER(REPLACE(COALESCE(u.uuid, ''), '-', '')) AS uuid,
+                COALESCE((
+                   SELECT MAX(s.time)
+                   FROM {prefix}session s
+                   WHERE s.user = u.rowid
+                ), u.time, 0) AS last_action
+            FROM {prefix}user u
+            WHERE
+                u.user LIKE {}
+                -- We try to exclude non-player entries
+                AND ({})
+            ORDER BY last_action DESC
+            LIMIT ?
+            "),
+            vec![Sql::new("?").bind(format!("%{}%", filter)), exclusions_clause]
+        ).bind(limit as u64);
+
+        read_players(self.database.query(&sql)?)
+    }
+
+    fn player_activity(&self, uuid: &Uuid) -> Result<Option<PlayerActivity>, DataError> {
+        let user = self.database.query(
+            &Sql::new(self.tables("SELECT rowid, user FROM {prefix}user WHERE uuid = ?"))
+                .bind(uuid.to_hyphenated().to_string())
+        )?;
+
+        let (user_id, name): (i64, String) = match user.first() {
+            Some(row) => (row.get(0)?, row.get(1)?),
+            None => return Ok(None)
+        };
+
+        let actions = self.database.query(&Sql::compose(
+            "
+            SELECT activity.action, COUNT(*), MIN(activity.time), MAX(activity.time)
+            FROM ({}) activity
+            GROUP BY activity.action
+            ",
+            vec![self.user_activity_sql(user_id)]
+        ))?;
+
+        let worlds = self.database.query(&Sql::compose(
+            &self.tables("
+            SELECT w.world, COUNT(*), MIN(activity.time), MAX(activity.time) AS last_action
+            FROM ({}) activity
+            JOIN {prefix}world w ON w.id = activity.wid
+            GROUP BY w.world
+            ORDER BY last_action DESC
+            "),
+            vec![self.user_activity_sql(user_id)]
+        ))?;
+
+        read_activity(name, actions, worlds).map(Some)
+    }
+
+    fn flows(&self, filters: &Filters, grouping: Grouping) -> Result<Vec<FlowRow>, DataError> {
+        query_flows(&self.database, self.container_history_sql(), filters, grouping)
+    }
+
+    fn transactions(&self, filters: &Filters, cursor: Option<u64>, limit: usize) -> Result<Vec<TransactionRow>, DataError> {
+        query_transactions(&self.database, self.container_history_sql(), filters, cursor, limit)
+    }
+
+    fn history(&self, after: u64, limit: usize) -> Result<Vec<HistoryRow>, DataError> {
+        query_history(&self.database, self.container_history_sql(), after, limit)
+    }
+
+    fn last_id(&self) -> Result<u64, DataError> {
+        let rows = self.database.query(&Sql::new(self.tables("SELECT COALESCE(MAX(rowid), 0) FROM {prefix}container")))?;
+        rows.first().map(|row| row.get(0)).unwrap_or(Ok(0))
+    }
+
+    fn ping(&self) -> Result<(), DataError> {
+        self.database.ping()
+    }
+
+    fn missing_tables(&self) -> Result<Vec<String>, DataError> {
+        self.database.missing_tables(&TABLES.iter().map(|table| format!("{}{}", self.prefix, table)).collect::<Vec<String>>())
+    }
+}
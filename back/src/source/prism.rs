@@ -0,0 +1,145 @@
+use uuid::Uuid;
+
+use crate::database::Player;
+use crate::players::PlayerExclusions;
+use crate::query::{Filters, Sql};
+use super::{DataError, DataSource, Database, Dialect, FlowRow, Grouping, HistoryRow, PlayerActivity, TransactionRow};
+use super::{query_flows, query_history, query_transactions, read_activity, read_players};
+
+
+/// The tables read from Prism.
+const TABLES: [&str; 6] = ["prism_data", "prism_actions", "prism_players", "prism_worlds", "prism_id_map", "prism_data_extra"];
+
+/// Reads data from [Prism](https://github.com/prism/Prism-Bukkit), which only supports MySQL.
+pub struct Prism {
+    database: Database
+}
+
+impl Prism {
+    pub fn new(database: Database) -> Result<Self, DataError> {
+        match database.dialect() {
+            Dialect::MySql => Ok(Prism { database }),
+            _ => Err(DataError::Config(String::from("Prism data can only be read from MySQL")))
+        }
+    }
+
+    /// The container history (see `crate::source`). Prism stores the amount of items of each
+    /// transaction in the JSON data of the extra table.
+    ///
+    /// Columns coming from joined tables are never NULL, even for orphan records (e.g. without
+    /// extra data): such records have an empty material or world, or a zero amount, rather
+    /// than failing every query reading them.
+    fn container_history_sql(&self) -> Sql {
+        Sql::new("
+            SELECT
+                d.id AS id,
+                a.action AS action,
+                COALESCE(b.material, '') AS material,
+                CASE WHEN a.action = 'item-insert' THEN 1 ELSE -1 END * COALESCE(CAST(JSON_EXTRACT(e.data, '$.amt') AS SIGNED), 0) AS amount_diff,
+                d.epoch AS epoch,
+                d.x AS x,
+                d.y AS y,
+                d.z AS z,
+                COALESCE(w.world, '') AS world,
+                COALESCE(HEX(p.player_uuid), '') AS player_uuid,
+                COALESCE(p.player, '') AS player_name
+            FROM prism_data d
+            LEFT JOIN prism_actions a ON a.action_id = d.action_id
+            LEFT JOIN prism_players p ON p.player_id = d.player_id
+            LEFT JOIN prism_worlds w ON w.world_id = d.world_id
+            LEFT JOIN prism_id_map b ON b.block_id = d.block_id
+            LEFT JOIN prism_data_extra e ON e.data_id = d.id
+            WHERE a.action IN ('item-insert', 'item-remove')
+        ")
+    }
+}
+
+impl DataSource for Prism {
+    fn players(&self, filter: &str, exclusions: Option<&PlayerExclusions>, limit: usize) -> Result<Vec<Player>, DataError> {
+        let exclusions_clause = match exclusions {
+            Some(exclusions) => exclusions.as_sql("p.player", "HEX(p.player_uuid)"),
+            None => Sql::new("TRUE")
+        };
+
+        let sql = Sql::compose(
+            "
+            SELECT
+                p.player AS name,
+                HEX(p.player_uuid) AS uuid,
+                COALESCE((
+                   SELECT epoch
+                   FROM prism_data d
+                   WHERE d.player_id = p.player_id
+                   ORDER BY epoch DESC
+                   LIMIT 1
+                ), 0) AS last_action
+            FROM prism_players p
+            WHERE
+                p.player LIKE {}
+                -- We try to exclude non-player entries
+                AND ({})
+            ORDER BY last_action DESC
+            LIMIT ?
+            ",
+            vec![Sql::new("?").bind(format!("%{}%", filter)), exclusions_clause]
+        ).bind(limit as u64);
+
+        read_players(self.database.query(&sql)?)
+    }
+
+    fn player_activity(&self, uuid: &Uuid) -> Result<Option<PlayerActivity>, DataError> {
+        let player = self.database.query(
+            &Sql::new("SELECT player_id, player FROM prism_players WHERE player_uuid = UNHEX(?)")
+                .bind(uuid.to_simple().to_string())
+        )?;
+
+        let (player_id, name): (u64, String) = match player.first() {
+            Some(row) => (row.get(0)?, row.get(1)?),
+            None => return Ok(None)
+        };
+
+        let actions = self.database.query(&Sql::new("
+            SELECT a.action, COUNT(*), MIN(d.epoch), MAX(d.epoch)
+            FROM prism_data d
+            JOIN prism_actions a ON a.action_id = d.action_id
+            WHERE d.player_id = ?
+            GROUP BY a.action
+        ").bind(player_id))?;
+
+        let worlds = self.database.query(&Sql::new("
+            SELECT w.world, COUNT(*), MIN(d.epoch), MAX(d.epoch) AS last_action
+            FROM prism_data d
+            JOIN prism_worlds w ON w.world_id = d.world_id
+            WHERE d.player_id = ?
+            GROUP BY w.world
+            ORDER BY last_action DESC
+        ").bind(player_id))?;
+
+        read_activity(name, actions, worlds).map(Some)
+    }
+
+    fn flows(&self, filters: &Filters, grouping: Grouping) -> Result<Vec<FlowRow>, DataError> {
+        query_flows(&self.database, self.container_history_sql(), filters, grouping)
+    }
+
+    fn transactions(&self, filters: &Filters, cursor: Option<u64>, limit: usize) -> Result<Vec<TransactionRow>, DataError> {
+        query_transactions(&self.database, self.container_history_sql(), filters, cursor, limit)
+    }
+
+    fn history(&self, after: u64, limit: usize) -> Result<Vec<HistoryRow>, DataError> {
+        query_history(&self.database, self.container_history_sql(), after, limit)
+    }
+
+    fn last_id(&self) -> Result<u64, DataError> {
+        let rows = self.database.query(&Sql::new("SELECT COALESCE(MAX(id), 0) FROM prism_data"))?;
+        rows.first().map(|row| row.get(0)).unwrap_or(Ok(0))
+    }
+
+    fn ping(&self) -> Result<(), DataError> {
+        self.database.ping()
+    }
+
+    fn missing_tables(&self) -> Result<Vec<String>, DataError> {
+        self.database.missing_tables(&TABLES.iter().map(|table| table.to_string()).collect::<Vec<String>>())
+    }
+}
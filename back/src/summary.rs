@@ -0,0 +1,559 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use chrono::Utc;
+use itertools::Itertools;
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::area::Areas;
+use crate::area_store::AreaStore;
+use crate::config::SummaryConfigInner;
+use crate::database::Flow;
+use crate::query::{Filters, Sql};
+use crate::source::{DataError, DataSource, Database, FlowRow, Grouping, Source};
+
+
+const DAY: i64 = 24 * 3600;
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+
+    CREATE TABLE IF NOT EXISTS state (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS flows (
+        day INTEGER NOT NULL,
+        area_set TEXT NOT NULL,
+        player_uuid TEXT NOT NULL,
+        material TEXT NOT NULL,
+        ratio INTEGER NOT NULL,
+        inserted INTEGER NOT NULL,
+        removed INTEGER NOT NULL,
+        insertions INTEGER NOT NULL,
+        removals INTEGER NOT NULL,
+        PRIMARY KEY (day, area_set, player_uuid, material)
+    );
+
+    CREATE TABLE IF NOT EXISTS players (
+        uuid TEXT PRIMARY KEY,
+        name TEXT NOT NULL
+    );
+";
+
+const UPSERT_FLOW_SQL: &str = "
+    INSERT INTO flows (day, area_set, player_uuid, material, ratio, inserted, removed, insertions, removals)
+    VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+    ON CONFLICT (day, area_set, player_uuid, material) DO UPDATE SET
+        ratio = ratio + excluded.ratio,
+        inserted = inserted + excluded.inserted,
+        removed = removed + excluded.removed,
+        insertions = insertions + excluded.insertions,
+        removals = removals + excluded.removals
+";
+
+
+/// The state of the ingestion into a summary store.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct SummaryStatus {
+    /// Whether the store caught up with the data source since it was last (re)built. Until
+    /// then, ratios are computed from the data source.
+    pub ready: bool,
+
+    /// The ID and timestamp of the last ingested transaction.
+    pub last_id: u64,
+    pub last_epoch: Option<i64>,
+
+    /// The ID of the latest record of the data source, and the number of records not ingested
+    /// yet (which may include records other than container transactions).
+    pub source_last_id: Option<u64>,
+    pub lag: Option<u64>,
+
+    /// When the ingester last ran, and the error it failed with, if any.
+    pub last_run: Option<i64>,
+    pub error: Option<String>,
+
+    /// The fingerprint of the areas the store was built for.
+    #[serde(skip)]
+    pub areas: Option<String>
+}
+
+/// A local SQLite store of the container transactions of a server, pre-aggregated per day, set
+/// of areas, player and material. A background ingester tails the data source by transaction ID
+/// to keep it up-to-date, so that ratios are computed without scanning the data source.
+///
+/// Transactions are keyed by the set of areas they happened in, so that ratios over several
+/// overlapping areas (e.g. an area and its sub-areas) still count each transaction once. As
+/// this depends on the areas, the store is rebuilt from scratch when areas change. Rollbacks
+/// done after a transaction was ingested are not reflected.
+pub(crate) struct Summary {
+    path: PathBuf,
+    status: Mutex<SummaryStatus>
+}
+
+impl Summary {
+    /// Opens the summary store at the given path, creating it if needed.
+    pub fn open(path: PathBuf) -> Result<Self, DataError> {
+        let connection = Connection::open(&path)?;
+        connection.execute_batch(SCHEMA)?;
+
+        let status = SummaryStatus {
+            last_id: read_state(&connection, "last_id")?.and_then(|id| id.parse().ok()).unwrap_or(0),
+            last_epoch: read_state(&connection, "last_epoch")?.and_then(|epoch| epoch.parse().ok()),
+            areas: read_state(&connection, "areas")?,
+            ..SummaryStatus::default()
+        };
+
+        Ok(Summary {
+            path,
+            status: Mutex::new(status)
+        })
+    }
+
+    /// Starts the background ingester of this store. It ingests batches of transactions until
+    /// it catches up with the data source, then waits for the configured interval.
+    pub fn start(self: Arc<Self>, source: Source, areas: Arc<AreaStore>, config: SummaryConfigInner) {
+        thread::spawn(move || loop {
+            let caught_up = match self.ingest(source.blocking(), &areas.get(), config.batch_size) {
+                Ok(caught_up) => caught_up,
+                Err(error) => {
+                    eprintln!("Unable to update summary store {:?}: {}", self.path, error);
+                    self.update_status(|status| status.error = Some(error.to_string()));
+                    true
+                }
+            };
+
+            if caught_up {
+                thread::sleep(Duration::from_secs(config.interval));
+            }
+        });
+    }
+
+    pub fn status(&self) -> SummaryStatus {
+        match self.status.lock() {
+            Ok(status) => status.clone(),
+            Err(poisoned) => poisoned.into_inner().clone()
+        }
+    }
+
+    /// Checks that the store caught up with the data source, and that it was built for the
+    /// given areas: when areas change, the store isn't used until the ingester rebuilt it.
+    pub fn is_ready(&self, areas: &Areas) -> bool {
+        let status = self.status();
+        status.ready && status.areas.as_deref() == Some(fingerprint(areas).as_str())
+    }
+
+    /// Runs blocking queries against the store on a dedicated thread.
+    pub async fn run<F, R>(self: &Arc<Self>, query: F) -> Result<R, DataError>
+        where F: FnOnce(&Summary) -> Result<R, DataError> + Send + 'static,
+              R: Send + 'static
+    {
+        let summary = Arc::clone(self);
+
+        rocket::tokio::task::spawn_blocking(move || query(&summary)).await
+            .map_err(|e| DataError::Query(e.to_string()))?
+    }
+
+    /// Aggregates the flow of items of the transactions matching the filters, like the
+    /// `flows` of a data source but at a one-day resolution: time bounds should be rounded to
+    /// days. Material filters are not supported, nor are time buckets.
+    pub fn flows(&self, filters: &Filters, grouping: Grouping) -> Result<Vec<FlowRow>, DataError> {
+        let sql = Sql::compose(
+            "
+            SELECT
+                f.area_set, f.player_uuid, COALESCE(MAX(p.name), ''), f.material, MIN(f.day), MAX(f.day),
+                SUM(f.ratio), SUM(f.inserted), SUM(f.removed), SUM(f.insertions), SUM(f.removals)
+            FROM flows f
+            LEFT JOIN players p ON p.uuid = f.player_uuid
+            WHERE {} AND {} AND {} AND {}
+            GROUP BY f.area_set, f.player_uuid, f.material
+            ",
+            vec![
+                filters.players.as_sql("f.player_uuid"),
+                filters.exclusions.as_ref()
+                    .map(|exclusions| exclusions.as_sql("COALESCE(p.name, '')", "f.player_uuid"))
+                    .unwrap_or(Sql::new("TRUE")),
+                filters.since.epoch.map(|since| Sql::new("f.day >= ?").bind(since)).unwrap_or(Sql::new("TRUE")),
+                filters.until.epoch.map(|until| Sql::new("f.day < ?").bind(until)).unwrap_or(Sql::new("TRUE"))
+            ]
+        );
+
+        let mut flows: BTreeMap<(String, String), FlowRow> = BTreeMap::new();
+
+        for row in Database::sqlite(self.path.clone()).query(&sql)? {
+            let area_set: String = row.get(0)?;
+
+            // Like with data sources, a transaction counts for the first requested area it
+            // happened in, requested areas being sorted by specificity.
+            let area = match filters.areas.iter().find(|area| area_set.split(',').any(|id| id == area.id)) {
+                Some(area) => area,
+                None => continue
+            };
+
+            let (group, name) = match grouping {
+                Grouping::None => (String::new(), String::new()),
+                Grouping::Player => (row.get(1)?, row.get(2)?),
+                Grouping::Area => (area.id.clone(), String::new()),
+                Grouping::Bucket(_) => return Err(DataError::Query(String::from("the summary store cannot be bucketed")))
+            };
+
+            let material: String = row.get(3)?;
+            let first_action: i64 = row.get(4)?;
+            let last_action: i64 = row.get::<i64>(5)? + DAY - 1;
+            let flow = Flow {
+                ratio: row.get(6)?,
+                inserted: row.get(7)?,
+                removed: row.get(8)?,
+                insertions: row.get(9)?,
+                removals: row.get(10)?
+            };
+
+            let entry = flows.entry((group.clone(), material.clone())).or_insert_with(|| FlowRow {
+                group,
+                name,
+                material,
+                flow: Flow::default(),
+                first_action,
+                last_action
+            });
+
+            entry.flow += flow;
+            entry.first_action = entry.first_action.min(first_action);
+            entry.last_action = entry.last_action.max(last_action);
+        }
+
+        Ok(flows.into_iter()
+            .map(|(_, row)| row)
+            .sorted_by_key(|row| (row.group.clone(), row.flow.ratio))
+            .collect())
+    }
+
+    /// Ingests the next batch of transactions, rebuilding the store first if the areas changed.
+    /// Returns whether the store caught up with the data source.
+    fn ingest(&self, source: &dyn DataSource, areas: &Areas, batch_size: usize) -> Result<bool, DataError> {
+        let fingerprint = fingerprint(areas);
+        let mut connection = Connection::open(&self.path)?;
+
+        if read_state(&connection, "areas")?.as_deref() != Some(fingerprint.as_str()) {
+            let transaction = connection.transaction()?;
+            transaction.execute_batch("DELETE FROM flows; DELETE FROM players; DELETE FROM state;")?;
+            write_state(&transaction, "areas", &fingerprint)?;
+            transaction.commit()?;
+
+            self.update_status(|status| *status = SummaryStatus { areas: Some(fingerprint.clone()), ..SummaryStatus::default() });
+        }
+
+        let last_id: u64 = read_state(&connection, "last_id")?.and_then(|id| id.parse().ok()).unwrap_or(0);
+        let rows = source.history(last_id, batch_size)?;
+        let caught_up = rows.len() < batch_size;
+
+        let mut flows: HashMap<(i64, String, String, String), Flow> = HashMap::new();
+        let mut players: HashMap<String, String> = HashMap::new();
+
+        for row in rows.iter() {
+            let area_set: String = areas.areas.values()
+                .filter(|area| area.contains(&row.world, row.x, row.y, row.z))
+                .map(|area| area.id.as_str())
+                .sorted()
+                .join(",");
+
+            if area_set.is_empty() {
+                continue;
+            }
+
+            let day = row.epoch.div_euclid(DAY) * DAY;
+            *flows.entry((day, area_set, row.player_uuid.clone(), row.material.clone())).or_default() += flow_of(row.amount_diff);
+            players.insert(row.player_uuid.clone(), row.player_name.clone());
+        }
+
+        let transaction = connection.transaction()?;
+        {
+            let mut upsert_flow = transaction.prepare_cached(UPSERT_FLOW_SQL)?;
+            for ((day, area_set, player_uuid, material), flow) in flows.iter() {
+                upsert_flow.execute(params![
+                    day, area_set, player_uuid, material,
+                    flow.ratio, flow.inserted, flow.removed, flow.insertions, flow.removals
+                ])?;
+            }
+
+            let mut upsert_player = transaction.prepare_cached(
+                "INSERT INTO players (uuid, name) VALUES (?, ?) ON CONFLICT (uuid) DO UPDATE SET name = excluded.name"
+            )?;
+            for (uuid, name) in players.iter() {
+                upsert_player.execute(params![uuid, name])?;
+            }
+        }
+
+        let last = rows.last().map(|row| (row.id, row.epoch));
+        if let Some((id, epoch)) = last {
+            write_state(&transaction, "last_id", &id.to_string())?;
+            write_state(&transaction, "last_epoch", &epoch.to_string())?;
+        }
+
+        transaction.commit()?;
+
+        let source_last_id = source.last_id().ok();
+
+        self.update_status(|status| {
+            if let Some((id, epoch)) = last {
+                status.last_id = id;
+                status.last_epoch = Some(epoch);
+            }
+
+            let last_id = status.last_id;
+            status.source_last_id = source_last_id;
+            status.lag = source_last_id.map(|source_last_id| source_last_id.saturating_sub(last_id));
+            status.last_run = Some(Utc::now().timestamp());
+            status.error = None;
+            status.ready |= caught_up;
+        });
+
+        Ok(caught_up)
+    }
+
+    fn update_status<F: FnOnce(&mut SummaryStatus)>(&self, change: F) {
+        match self.status.lock() {
+            Ok(mut status) => change(&mut *status),
+            Err(poisoned) => change(&mut *poisoned.into_inner())
+        }
+    }
+}
+
+
+/// The flow of a single transaction, with the same rules as the SQL aggregates of the data
+/// sources.
+fn flow_of(amount_diff: i64) -> Flow {
+    Flow {
+        ratio: amount_diff,
+        inserted: amount_diff.max(0),
+        removed: (-amount_diff).max(0),
+        insertions: (amount_diff > 0) as i64,
+        removals: (amount_diff < 0) as i64
+    }
+}
+
+/// Hashes the geometry of the areas, to detect when the store must be rebuilt. Names are left
+/// out, as renaming an area doesn't change the transactions in it.
+fn fingerprint(areas: &Areas) -> String {
+    let geometry: Vec<_> = areas.areas.values()
+        .sorted_by(|a, b| a.id.cmp(&b.id))
+        .map(|area| (&area.id, &area.world, &area.shapes, &area.exclude))
+        .collect();
+
+    format!("{:x}", Sha256::digest(serde_json::to_string(&geometry).unwrap_or_default().as_bytes()))
+}
+
+fn read_state(connection: &Connection, key: &str) -> Result<Option<String>, rusqlite::Error> {
+    connection.query_row("SELECT value FROM state WHERE key = ?", params![key], |row| row.get(0)).optional()
+}
+
+fn write_state(connection: &Connection, key: &str, value: &str) -> Result<(), rusqlite::Error> {
+    connection.execute(
+        "INSERT INTO state (key, value) VALUES (?, ?) ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+        params![key, value]
+    ).map(|_| ())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::area::Area;
+    use crate::config::ConfigAreaEntry;
+    use crate::database::Player;
+    use crate::params::{TimeBound, Uuids};
+    use crate::players::PlayerExclusions;
+    use crate::source::{HistoryRow, PlayerActivity, TransactionRow};
+
+    /// A data source only holding container history rows, in ID order.
+    struct History(Vec<HistoryRow>);
+
+    impl DataSource for History {
+        fn players(&self, _: &str, _: Option<&PlayerExclusions>, _: usize) -> Result<Vec<Player>, DataError> {
+            unimplemented!()
+        }
+
+        fn player_activity(&self, _: &Uuid) -> Result<Option<PlayerActivity>, DataError> {
+            unimplemented!()
+        }
+
+        fn flows(&self, _: &Filters, _: Grouping) -> Result<Vec<FlowRow>, DataError> {
+            unimplemented!()
+        }
+
+        fn transactions(&self, _: &Filters, _: Option<u64>, _: usize) -> Result<Vec<TransactionRow>, DataError> {
+            unimplemented!()
+        }
+
+        fn history(&self, after: u64, limit: usize) -> Result<Vec<HistoryRow>, DataError> {
+            Ok(self.0.iter().filter(|row| row.id > after).take(limit).cloned().collect())
+        }
+
+        fn last_id(&self) -> Result<u64, DataError> {
+            Ok(self.0.last().map(|row| row.id).unwrap_or(0))
+        }
+
+        fn ping(&self) -> Result<(), DataError> {
+            Ok(())
+        }
+
+        fn missing_tables(&self) -> Result<Vec<String>, DataError> {
+            Ok(vec![])
+        }
+    }
+
+    fn row(id: u64, epoch: i64, player: &str, material: &str, amount_diff: i64, (x, y, z): (i64, i64, i64)) -> HistoryRow {
+        HistoryRow {
+            id,
+            epoch,
+            material: material.to_string(),
+            amount_diff,
+            x,
+            y,
+            z,
+            world: String::from("world"),
+            player_uuid: format!("{:0>32}", player.len()),
+            player_name: player.to_string()
+        }
+    }
+
+    fn areas(config: &str) -> Areas {
+        let entries: HashMap<String, ConfigAreaEntry> = toml::from_str(config).expect("invalid TOML");
+        Areas::try_from(entries).expect("invalid areas")
+    }
+
+    const AREAS: &str = r#"
+        bank = { name = "Bank", world = "world", pos1 = [0, 0, 0], pos2 = [10, 10, 10] }
+        town = { name = "Town", world = "world", pos1 = [0, 0, 0], pos2 = [100, 100, 100] }
+    "#;
+
+    /// A summary store in a new temporary file.
+    fn summary(name: &str) -> Summary {
+        let path = std::env::temp_dir().join(format!("panoptes-summary-{}-{}.db", name, std::process::id()));
+        for suffix in &["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+        Summary::open(path).unwrap()
+    }
+
+    fn flows(summary: &Summary, areas: &Areas, grouping: Grouping) -> Vec<(String, String, String, i64)> {
+        let filters = Filters::new(areas.areas.values().cloned().collect::<Vec<Area>>(), Uuids::any(), TimeBound::unbounded(), TimeBound::unbounded());
+
+        summary.flows(&filters, grouping).unwrap().into_iter()
+            .map(|row| (row.group, row.name, row.material, row.flow.ratio))
+            .sorted()
+            .collect()
+    }
+
+    fn history() -> History {
+        History(vec![
+            row(1, 10, "Alice", "diamond", 5, (5, 5, 5)),
+            row(2, DAY + 10, "Bob", "diamond", -2, (50, 5, 50)),
+            row(3, DAY + 20, "Alice", "diamond", 3, (500, 5, 500)),
+            row(4, DAY + 30, "Alice", "stone", 64, (50, 5, 50))
+        ])
+    }
+
+    #[test]
+    fn ingests_transactions_by_area_set() {
+        let summary = summary("ingest");
+        let areas = areas(AREAS);
+
+        assert!(summary.ingest(&history(), &areas, 100).unwrap());
+
+        let status = summary.status();
+        assert!(status.ready);
+        assert_eq!((status.last_id, status.last_epoch, status.lag), (4, Some(DAY + 30), Some(0)));
+
+        // The first transaction is in both areas, and counts for the first one only.
+        assert_eq!(flows(&summary, &areas, Grouping::Area), vec![
+            (String::from("bank"), String::new(), String::from("diamond"), 5),
+            (String::from("town"), String::new(), String::from("diamond"), -2),
+            (String::from("town"), String::new(), String::from("stone"), 64)
+        ]);
+
+        let town = areas.areas.values().filter(|area| area.id == "town").cloned().collect::<Vec<Area>>();
+        let filters = Filters::new(town, Uuids::any(), TimeBound::unbounded(), TimeBound::unbounded());
+        let rows = summary.flows(&filters, Grouping::Player).unwrap();
+        let diamonds: Vec<(&str, i64)> = rows.iter()
+            .filter(|row| row.material == "diamond")
+            .map(|row| (row.name.as_str(), row.flow.ratio))
+            .sorted()
+            .collect();
+        assert_eq!(diamonds, vec![("Alice", 5), ("Bob", -2)]);
+    }
+
+    #[test]
+    fn ingests_in_batches() {
+        let summary = summary("batches");
+        let areas = areas(AREAS);
+        let history = history();
+
+        assert!(!summary.ingest(&history, &areas, 3).unwrap());
+        assert!(!summary.status().ready);
+        assert!(!summary.is_ready(&areas));
+        assert_eq!((summary.status().last_id, summary.status().lag), (3, Some(1)));
+
+        assert!(summary.ingest(&history, &areas, 3).unwrap());
+        assert!(summary.is_ready(&areas));
+        assert_eq!((summary.status().last_id, summary.status().lag), (4, Some(0)));
+
+        // Once caught up, new transactions are ingested from the last one.
+        let mut history = history;
+        history.0.push(row(5, DAY + 40, "Bob", "stone", -10, (50, 5, 50)));
+        assert!(summary.ingest(&history, &areas, 3).unwrap());
+        assert!(flows(&summary, &areas, Grouping::Area).contains(&(String::from("town"), String::new(), String::from("stone"), 54)));
+    }
+
+    #[test]
+    fn rebuilds_when_areas_change() {
+        let summary = summary("rebuild");
+        let areas = areas(AREAS);
+
+        summary.ingest(&history(), &areas, 100).unwrap();
+        assert!(summary.is_ready(&areas));
+
+        // Renaming an area doesn't change the transactions in it.
+        let renamed = self::areas(&AREAS.replace("\"Bank\"", "\"Old bank\""));
+        assert!(summary.is_ready(&renamed));
+
+        // Moving one does: the store is not used until it's rebuilt.
+        let moved = self::areas(&AREAS.replace("pos2 = [10, 10, 10]", "pos2 = [60, 10, 60]"));
+        assert!(!summary.is_ready(&moved));
+
+        assert!(summary.ingest(&history(), &moved, 100).unwrap());
+        assert!(summary.is_ready(&moved));
+        assert!(!summary.is_ready(&areas));
+
+        assert_eq!(flows(&summary, &moved, Grouping::Area), vec![
+            (String::from("bank"), String::new(), String::from("diamond"), 3),
+            (String::from("bank"), String::new(), String::from("stone"), 64)
+        ]);
+    }
+
+    #[test]
+    fn is_not_ready_when_reopened() {
+        let summary = summary("reopen");
+        let areas = areas(AREAS);
+
+        summary.ingest(&history(), &areas, 100).unwrap();
+
+        let reopened = Summary::open(summary.path.clone()).unwrap();
+        assert_eq!(reopened.status().last_id, 4);
+        assert!(!reopened.is_ready(&areas));
+
+        assert!(reopened.ingest(&history(), &areas, 100).unwrap());
+        assert!(reopened.is_ready(&areas));
+        assert_eq!(flows(&reopened, &areas, Grouping::Area), flows(&summary, &areas, Grouping::Area));
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+
+
+/// The roles a user can have. Each role grants the permissions of the previous ones.
+///
+/// - Viewers can read the aggregated data: areas, ratios, timelines, leaderboards, and the list
+///   of players.
+/// - Moderators can also investigate individual players: profiles and transactions.
+/// - Administrators can also manage areas and read the audit log.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    Moderator,
+    Admin
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(role: &str) -> Result<Self, Self::Err> {
+        match role.to_lowercase().as_str() {
+            "viewer" => Ok(Role::Viewer),
+            "moderator" => Ok(Role::Moderator),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("unknown role {} (expected viewer, moderator or admin)", role))
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Viewer => write!(f, "viewer"),
+            Role::Moderator => write!(f, "moderator"),
+            Role::Admin => write!(f, "admin")
+        }
+    }
+}
+
+
+/// A user, as stored in the users file. Secrets are never stored in clear: the password is
+/// hashed with Argon2, and API tokens (random and long, so a slow hash is not needed) with
+/// SHA-256, keyed by a label.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredUser {
+    pub role: Role,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub tokens: BTreeMap<String, String>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UsersFile {
+    #[serde(default)]
+    pub users: BTreeMap<String, StoredUser>
+}
+
+/// Stores the users in a local TOML file, managed with the `users` command line (see
+/// `crate::cli`). The file is read again when it's modified, so changes apply without restart.
+pub struct UserStore {
+    path: PathBuf,
+    loaded: RwLock<(Option<SystemTime>, UsersFile)>
+}
+
+impl UserStore {
+    pub fn new(path: PathBuf) -> Self {
+        UserStore {
+            path,
+            loaded: RwLock::new((None, UsersFile::default()))
+        }
+    }
+
+    /// Returns the current users, reading the file again if it changed since last time. A
+    /// missing file means there are no users.
+    pub fn users(&self) -> Result<UsersFile, String> {
+        let modified = fs::metadata(&self.path).and_then(|metadata| metadata.modified()).ok();
+
+        if let Ok(loaded) = self.loaded.read() {
+            if loaded.0.is_some() && loaded.0 == modified {
+                return Ok(loaded.1.clone());
+            }
+        }
+
+        let users: UsersFile = match modified {
+            Some(_) => fs::read_to_string(&self.path)
+                .map_err(|e| e.to_string())
+                .and_then(|content| toml::from_str(&content).map_err(|e| e.to_string()))
+                .map_err(|e| format!("Unable to read users file {:?}: {}", self.path, e))?,
+            None => UsersFile::default()
+        };
+
+        if let Ok(mut loaded) = self.loaded.write() {
+            *loaded = (modified, users.clone());
+        }
+
+        Ok(users)
+    }
+
+    /// Saves the users to the file.
+    pub fn save(&self, users: &UsersFile) -> Result<(), String> {
+        // Converting to a TOML value first ensures plain values are written before tables.
+        toml::Value::try_from(users)
+            .and_then(|value| toml::to_string(&value))
+            .map_err(|e| e.to_string())
+            .and_then(|content| fs::write(&self.path, content).map_err(|e| e.to_string()))
+            .map_err(|e| format!("Unable to save users file {:?}: {}", self.path, e))
+    }
+
+    /// Returns the user with the given name and password, if they match.
+    pub fn authenticate_password(&self, name: &str, password: &str) -> Option<(String, Role)> {
+        let users = self.users().ok()?;
+        let user = users.users.get(name)?;
+
+        match argon2::verify_encoded(user.password.as_ref()?, password.as_bytes()) {
+            Ok(true) => Some((name.to_string(), user.role)),
+            _ => None
+        }
+    }
+
+    /// Returns the user owning the given API token, if any.
+    pub fn authenticate_token(&self, token: &str) -> Option<(String, Role)> {
+        let hash = hash_token(token);
+
+        self.users().ok()?.users.into_iter()
+            .find(|(_, user)| user.tokens.values().any(|stored| stored == &hash))
+            .map(|(name, user)| (name, user.role))
+    }
+}
+
+
+/// Hashes a password with Argon2 and a random salt.
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt: [u8; 16] = rand::random();
+    argon2::hash_encoded(password.as_bytes(), &salt, &argon2::Config::default()).map_err(|e| e.to_string())
+}
+
+/// Hashes a token. Unlike passwords, tokens are random and long enough not to need a salt.
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Generates a new random token, used both for API tokens and sessions.
+pub fn generate_token() -> String {
+    let bytes: [u8; 32] = rand::random();
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
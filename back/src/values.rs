@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+
+use crate::config::{ItemValuesConfig, ItemValuesConfigInner};
+
+
+/// Stores the weight of each material, used to compute a value-weighted ratio so that taking a
+/// stack of diamonds and putting back a stack of cobblestone is not considered fair.
+///
+/// Materials are matched first exactly, then against the wildcard patterns (the most specific
+/// pattern—the one with the most non-wildcard characters—wins), then fall back to the default
+/// weight. The `minecraft:` namespace is optional, both in the configuration and in the source data.
+#[derive(Debug, Clone)]
+pub struct ItemValues {
+    pub default: f64,
+    exact: HashMap<String, f64>,
+    patterns: Vec<(String, f64)>
+}
+
+impl ItemValues {
+    /// Returns item values where every material weights `1`, used when configuration is missing.
+    /// The weighted values are then equal to the raw items counts.
+    pub fn empty() -> Self {
+        ItemValues {
+            default: 1.0,
+            exact: HashMap::new(),
+            patterns: vec![]
+        }
+    }
+
+    /// Returns the weight of the given material.
+    pub fn weight_of(&self, material: &str) -> f64 {
+        let material = strip_namespace(material);
+
+        if let Some(weight) = self.exact.get(material) {
+            return *weight;
+        }
+
+        self.patterns.iter()
+            .find(|(pattern, _)| glob_matches(pattern, material))
+            .map(|(_, weight)| *weight)
+            .unwrap_or(self.default)
+    }
+}
+
+impl From<ItemValuesConfig> for ItemValues {
+    fn from(config: ItemValuesConfig) -> Self {
+        match config.item_values {
+            Some(config) => config.into(),
+            None => ItemValues::empty()
+        }
+    }
+}
+
+impl From<ItemValuesConfigInner> for ItemValues {
+    fn from(config: ItemValuesConfigInner) -> Self {
+        let (mut patterns, exact): (Vec<(String, f64)>, Vec<(String, f64)>) = config.materials
+            .into_iter()
+            .map(|(material, weight)| (strip_namespace(&material).to_lowercase(), weight))
+            .partition(|(material, _)| material.contains('*') || material.contains('?'));
+
+        // Most specific patterns first, so `diamond_*` wins over `*`.
+        patterns.sort_by_key(|(pattern, _)| -(pattern.chars().filter(|c| *c != '*' && *c != '?').count() as i64));
+
+        ItemValues {
+            default: config.default,
+            exact: exact.into_iter().collect(),
+            patterns
+        }
+    }
+}
+
+fn strip_namespace(material: &str) -> &str {
+    material.strip_prefix("minecraft:").unwrap_or(material)
+}
+
+/// Checks if the text matches the glob pattern, where `*` matches any sequence of characters
+/// (including none) and `?` matches exactly one character.
+pub fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            p = star_p + 1;
+            t = star_t + 1;
+            backtrack = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_matches_literals() {
+        assert!(glob_matches("diamond", "diamond"));
+        assert!(!glob_matches("diamond", "diamonds"));
+        assert!(!glob_matches("diamonds", "diamond"));
+        assert!(glob_matches("", ""));
+        assert!(!glob_matches("", "diamond"));
+    }
+
+    #[test]
+    fn glob_matches_wildcards() {
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("*", "diamond"));
+        assert!(glob_matches("*_ore", "diamond_ore"));
+        assert!(glob_matches("*_ore", "deepslate_diamond_ore"));
+        assert!(!glob_matches("*_ore", "diamond_ore_block"));
+        assert!(glob_matches("diamond_*", "diamond_"));
+        assert!(glob_matches("*diamond*", "deepslate_diamond_ore"));
+        assert!(glob_matches("a*b*c", "aXbYc"));
+        assert!(glob_matches("a*b*c", "abbbc"));
+        assert!(!glob_matches("a*b*c", "aXbY"));
+        assert!(glob_matches("**", "diamond"));
+    }
+
+    #[test]
+    fn glob_matches_single_characters() {
+        assert!(glob_matches("?at", "cat"));
+        assert!(!glob_matches("?at", "at"));
+        assert!(!glob_matches("?at", "chat"));
+        assert!(glob_matches("*?", "a"));
+        assert!(!glob_matches("*?", ""));
+        assert!(glob_matches("é?", "éa"));
+    }
+}
@@ -0,0 +1,30 @@
+use std::time::Duration;
+
+use serde_json::json;
+
+
+/// Discord rejects messages longer than this number of characters.
+const MAX_MESSAGE_LENGTH: usize = 2000;
+
+/// Posts a message to a Discord-compatible webhook. Messages too long are truncated.
+///
+/// This is blocking and should only be called from background threads.
+pub fn post_message(url: &str, content: &str) -> Result<(), String> {
+    let content = match content.chars().count() > MAX_MESSAGE_LENGTH {
+        true => format!("{}…", content.chars().take(MAX_MESSAGE_LENGTH - 1).collect::<String>()),
+        false => content.to_string()
+    };
+
+    let agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(10))
+        .build();
+
+    agent.post(url)
+        .send_json(json!({ "username": "Panoptes", "content": content }))
+        .map(|_| ())
+        // Webhook URLs contain their secret token, so they are kept out of errors.
+        .map_err(|error| match error {
+            ureq::Error::Status(status, _) => format!("Unable to post to webhook: HTTP status {}", status),
+            ureq::Error::Transport(transport) => format!("Unable to post to webhook: {}", transport.kind())
+        })
+}
@@ -1,9 +1,10 @@
 import Vue from 'vue';
+import serverPath from './server';
 
 const areaApi = {
     list: function(filter)
     {
-        return Vue.axios.get('areas');
+        return Vue.axios.get(serverPath('areas'));
     }
 }
 
@@ -0,0 +1,18 @@
+import Vue from 'vue';
+
+const authApi = {
+    login: function(name, password)
+    {
+        return Vue.axios.post('login', {name: name, password: password});
+    },
+    logout: function()
+    {
+        return Vue.axios.post('logout');
+    },
+    me: function()
+    {
+        return Vue.axios.get('me');
+    }
+}
+
+export default authApi;
@@ -1,4 +1,5 @@
 import Vue from 'vue';
+import serverPath from './server';
 
 function _buildRatioParams(players, areas)
 {
@@ -24,7 +25,7 @@ const ratioApi = {
         }
 
         let uri = _buildRatioParams(playerUuids, areaIds);
-        return Vue.axios.get('ratios' + uri);
+        return Vue.axios.get(serverPath('ratios') + uri);
         /*return Promise.resolve({ data:
             JSON.parse('{"users":[{"uuid":"","username":""},{"uuid":"","username":""}],"areas"' +
                 ':["banque","point-c","ancienne-banque"],"global":-78444,"detail":{"minecraft:stone":-4877,"minecraft:cake":278}}')
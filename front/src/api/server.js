@@ -0,0 +1,8 @@
+/**
+ * Data and areas endpoints are scoped to a server: prefixes the path of such an endpoint with
+ * the server set in the VUE_APP_SERVER environment variable.
+ */
+export default function serverPath(path)
+{
+    return 'servers/' + (process.env.VUE_APP_SERVER || 'default') + '/' + path;
+}
@@ -10,9 +10,9 @@ import store from "./../store/index"
 // axios.defaults.headers.post['Content-Type'] = 'application/x-www-form-urlencoded';
 
 let config = {
-  baseURL: process.env.VUE_APP_API_URL
+  baseURL: process.env.VUE_APP_API_URL,
   // timeout: 60 * 1000, // Timeout
-  // withCredentials: true, // Check cross-site Access-Control
+  withCredentials: true, // Sends the session cookie set by /login
 };
 
 const _axios = axios.create(config);
@@ -35,6 +35,12 @@ _axios.interceptors.response.use(
     return response;
   },
   function(error) {
+    // Without a valid session, the login view is displayed instead of an error.
+    if (error.response && error.response.status === 401) {
+      store.commit("setUser", null);
+      return Promise.reject(error);
+    }
+
     console.log("Une erreur est survenue. Vérifiez l'accès à l'api.");
     store.commit("addError", {type: 'error', description: "Une erreur est survenue. Vérifiez l'accès à l'api."});
     return Promise.reject(error);
@@ -6,9 +6,14 @@ Vue.use(Vuex)
 const store = new Vuex.Store({
   state: {
     errors: [],
-    errorIdsCount: 0
+    errorIdsCount: 0,
+    user: null
   },
   mutations: {
+    setUser(state, user)
+    {
+      state.user = user;
+    },
     addError(state, payload)
     {
       state.errors.push({id: state.errorIdsCount, type: payload.type, description: payload.description, show: true});